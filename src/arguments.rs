@@ -1,9 +1,40 @@
 use std::io::{Error, ErrorKind};
 use std::path::PathBuf;
-use clap::{Args, command, Parser, Subcommand, value_parser};
+use clap::{Args, Parser, Subcommand, ValueEnum, value_parser};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use gpg_ssh::GpgSshError;
+use gpg_ssh::gpg::{Expire, KeyAlgo, KeyQuery};
 use regex::Regex;
 
-/// Generate SSH key (ed25519)
+fn invalid_input(err: GpgSshError) -> Error {
+    match err {
+        GpgSshError::InvalidArgument(msg) => Error::new(ErrorKind::InvalidInput, msg),
+        err => Error::new(ErrorKind::InvalidInput, err.to_string()),
+    }
+}
+
+fn parse_key_algo(name: &str) -> Result<KeyAlgo, Error> {
+    name.parse().map_err(invalid_input)
+}
+
+fn parse_expire(expire: &str) -> Result<Expire, Error> {
+    expire.parse().map_err(invalid_input)
+}
+
+fn parse_key_query(query: &str) -> Result<KeyQuery, Error> {
+    query.parse().map_err(invalid_input)
+}
+
+/// A subkey `create` can add
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SubkeyArg {
+    Sign,
+    #[value(alias = "encrypt")]
+    Encr,
+    Auth,
+}
+
+/// Generate SSH key (ed25519 by default)
 #[derive(Args, Debug, PartialEq)]
 pub struct CreateArgs {
     #[arg(
@@ -19,6 +50,151 @@ pub struct CreateArgs {
         help = "Set the email of the certificate",
     )]
     pub email: String,
+
+    #[arg(
+        long,
+        default_value_t = KeyAlgo::ED25519,
+        value_parser = PossibleValuesParser::new(KeyAlgo::ALL.map(|key_algo| key_algo.name()))
+            .try_map(|name| parse_key_algo(&name)),
+        help = "The algorithm of the primary key and the subkeys, pick RSA or a NIST curve for servers refusing ed25519",
+    )]
+    pub algo: KeyAlgo,
+
+    #[arg(
+        long,
+        help = "Set the comment of the user id [default: SSH Key]",
+    )]
+    pub comment: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = Expire::Never,
+        value_parser = parse_expire,
+        help = "When the primary key expires, and the subkeys without an expiry of their own: \
+        <n> days, <n>w, <n>m or <n>y, a YYYY-MM-DD date or never",
+    )]
+    pub expire: Expire,
+
+    #[arg(
+        long,
+        value_parser = parse_expire,
+        help = "When the sign subkey expires [default: --expire]",
+    )]
+    pub sign_expire: Option<Expire>,
+
+    #[arg(
+        long,
+        value_parser = parse_expire,
+        help = "When the encryption subkey expires [default: --expire]",
+    )]
+    pub encr_expire: Option<Expire>,
+
+    #[arg(
+        long,
+        value_parser = parse_expire,
+        help = "When the auth subkey expires [default: --expire]",
+    )]
+    pub auth_expire: Option<Expire>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        num_args = 1..,
+        default_values_t = [SubkeyArg::Sign, SubkeyArg::Encr, SubkeyArg::Auth],
+        help = "The subkeys to add to the primary key, e.g. `--subkeys auth`. \
+        The key is only enabled for SSH if an auth subkey is added",
+    )]
+    pub subkeys: Vec<SubkeyArg>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Do not add an encryption subkey, even if listed in --subkeys",
+    )]
+    pub no_encrypt: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Keep the partially created key if a step fails (used for debugging), \
+        by default it is deleted again",
+    )]
+    pub keep_partial: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = value_parser!(PathBuf),
+        group = "passphrase",
+        help = "Read the passphrase of the new key from the first line of the file, instead of asking with pinentry",
+    )]
+    pub passphrase_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FD",
+        group = "passphrase",
+        help = "Read the passphrase of the new key from the file descriptor, e.g. 0 for stdin",
+    )]
+    pub passphrase_fd: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "VAR",
+        group = "passphrase",
+        help = "Read the passphrase of the new key from the environment variable",
+    )]
+    pub passphrase_env: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        group = "passphrase",
+        help = "Do not protect the new key with a passphrase",
+    )]
+    pub no_protection: bool,
+}
+
+/// Add an SSH auth subkey to an existing GPG key
+#[derive(Args, Debug, PartialEq)]
+pub struct AddAuthArgs {
+    #[arg(
+        long,
+        value_name = "ID",
+        help = "The key to add the auth subkey to, without the fuzzy finder. \
+        An id can be a fingerprint, a 16 or 8 hex key id or a part of the user id",
+    )]
+    pub key: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = KeyAlgo::ED25519,
+        value_parser = PossibleValuesParser::new(KeyAlgo::ALL.map(|key_algo| key_algo.name()))
+            .try_map(|name| parse_key_algo(&name)),
+        help = "The algorithm of the auth subkey",
+    )]
+    pub algo: KeyAlgo,
+
+    #[arg(
+        long,
+        default_value_t = Expire::Never,
+        value_parser = parse_expire,
+        help = "When the auth subkey expires: <n> days, <n>w, <n>m or <n>y, a YYYY-MM-DD date or never",
+    )]
+    pub expire: Expire,
+}
+
+/// How to print the keys
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Table,
+    /// A JSON array with all the keys
+    Json,
+    /// One JSON object per key and line
+    Jsonl,
 }
 
 /// List all the SSH Keys in GnuPG
@@ -30,6 +206,21 @@ pub struct ListArgs {
         help = "Include the key id",
     )]
     pub include_key_id: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Include keys where only the public key is available (e.g. keys of others)",
+    )]
+    pub include_public_only: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Table,
+        help = "The format of the output",
+    )]
+    pub output: OutputFormat,
 }
 
 /// Toggle which GPG are enabled ot be used by the SSH agent
@@ -39,8 +230,49 @@ pub struct ToggleArgs {
         long,
         default_value_t = false,
         help = "disable all GPG key marked for SSH usage",
+        conflicts_with_all = ["enable", "disable", "only"],
     )]
     pub disable_all: bool,
+
+    #[arg(
+        long,
+        num_args = 1..,
+        value_name = "ID",
+        help = "Enable the keys without the fuzzy finder. \
+        An id can be a fingerprint, a keygrip, an openpgp:0x... id or a part of the user id",
+    )]
+    pub enable: Vec<String>,
+
+    #[arg(
+        long,
+        num_args = 1..,
+        value_name = "ID",
+        help = "Disable the keys without the fuzzy finder (see --enable for the ids)",
+    )]
+    pub disable: Vec<String>,
+
+    #[arg(
+        long,
+        num_args = 1..,
+        value_name = "ID",
+        conflicts_with_all = ["enable", "disable"],
+        help = "Enable exactly these keys and disable all others (see --enable for the ids)",
+    )]
+    pub only: Vec<String>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Include keys where only the public key is available (e.g. keys of others)",
+    )]
+    pub include_public_only: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Use expired or revoked keys anyway",
+    )]
+    pub force: bool,
 }
 
 /// Parse public key to `ssh-copy-id` and have it upload to the server
@@ -60,6 +292,13 @@ pub struct CopyIdArgs {
         help = "Keep the temporary files create (used for debugging)",
     )]
     pub keep_temp_files: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Use expired or revoked keys anyway",
+    )]
+    pub force: bool,
 }
 
 fn verify_lookup_hex(hex_string: &str) -> Result<String, Error> {
@@ -72,15 +311,93 @@ fn verify_lookup_hex(hex_string: &str) -> Result<String, Error> {
     }
 }
 
+/// How to encode the public SSH keys
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum KeyFormat {
+    /// One line per key: `ssh-ed25519 AAAA... openpgp:0x...`
+    #[default]
+    Openssh,
+    /// `---- BEGIN SSH2 PUBLIC KEY ----` (`ssh-keygen -e`)
+    Rfc4716,
+    /// `-----BEGIN PUBLIC KEY-----` (PKCS#8 / SubjectPublicKeyInfo)
+    Pem,
+    /// One JSON Web Key per line
+    Jwk,
+    /// One `known_hosts` line per key for --host
+    KnownHosts,
+}
+
 /// Prints the Public SSH Key for the selected GPG key
 #[derive(Args, Debug, PartialEq)]
 pub struct PublicKeyArgs {
     #[arg(
         long,
         value_parser = verify_lookup_hex,
-        help = "Lookup and return GPG Key used to create the SSH",
+        help = "Lookup and return GPG Key used to create the SSH (see `lookup` for other ids)",
     )]
     pub lookup_hex: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Table,
+        help = "The format of the output",
+    )]
+    pub output: OutputFormat,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = KeyFormat::Openssh,
+        conflicts_with_all = ["lookup_hex", "output"],
+        help = "How to encode the public keys",
+    )]
+    pub format: KeyFormat,
+
+    #[arg(
+        long,
+        required_if_eq("format", "known-hosts"),
+        help = "The host (or comma separated hosts) of the known_hosts lines",
+    )]
+    pub host: Option<String>,
+}
+
+/// Find the GPG key and subkey of a key id, fingerprint, keygrip, SSH fingerprint or SSH public key
+#[derive(Args, Debug, PartialEq)]
+pub struct LookupArgs {
+    #[arg(
+        value_parser = parse_key_query,
+        required_unless_present_any = ["authorized_keys", "pubkey"],
+        conflicts_with_all = ["authorized_keys", "pubkey"],
+        help = "A key id (8 or 16 hex, also openpgp:0x...), a fingerprint, a keygrip, \
+        or an SSH fingerprint (SHA256:... or MD5:...)",
+    )]
+    pub id: Option<KeyQuery>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = value_parser!(PathBuf),
+        conflicts_with = "pubkey",
+        help = "Report which GPG key each line of the authorized_keys file is, lines with options included",
+    )]
+    pub authorized_keys: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = value_parser!(PathBuf),
+        help = "Report which GPG key the SSH public key (.pub file, `-` for stdin) is",
+    )]
+    pub pubkey: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Table,
+        help = "The format of the output",
+    )]
+    pub output: OutputFormat,
 }
 
 #[derive(Args, Debug, PartialEq)]
@@ -90,10 +407,12 @@ pub struct CompletionArgs {
 #[derive(Subcommand, Debug, PartialEq)]
 pub(crate) enum SubCommands {
     Create(CreateArgs),
+    AddAuth(AddAuthArgs),
     List(ListArgs),
     Toggle(ToggleArgs),
     CopyId(CopyIdArgs),
     PublicKey(PublicKeyArgs),
+    Lookup(LookupArgs),
 }
 
 /// A CLI to help managing SSH keys using GnuPG keystore
@@ -115,7 +434,7 @@ pub struct MainArgs {
         long,
         env = "SSH_AUTH_SOCK",
         value_parser = value_parser!(PathBuf),
-        help = "Path to the GPG Agent socket for SSH",
+        help = "Path to the GPG Agent socket for SSH, used to check which keys ssh is offered after `create`, `add-auth` and `toggle`",
     )]
     pub ssh_auth_sock: PathBuf,
 
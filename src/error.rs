@@ -0,0 +1,126 @@
+use std::fmt::{Display, Formatter};
+use std::string::FromUtf8Error;
+
+pub type Result<T> = std::result::Result<T, GpgSshError>;
+
+/// The errors returned by the functions of this crate
+#[derive(Debug)]
+pub enum GpgSshError {
+    /// The program could not be started (most likely it is not installed)
+    Spawn {
+        program: String,
+        source: std::io::Error,
+    },
+    /// The program was started, but exited with a non-zero exit code
+    CommandFailed {
+        program: String,
+        args: Vec<String>,
+        stdout: String,
+        stderr: String,
+        exit_code: Option<i32>,
+    },
+    /// The output of a program was not valid UTF-8
+    Utf8 {
+        program: String,
+        source: FromUtf8Error,
+    },
+    /// The output of a program did not look like expected
+    Parse(String),
+    /// The interactive fuzzy finder failed
+    Fuzzy(String),
+    /// The key is expired or revoked, so it should not be used for SSH
+    UnusableKey {
+        key: String,
+        reason: String,
+    },
+    /// No key matched the query
+    KeyNotFound(String),
+    /// More than one key matched the query
+    AmbiguousKey {
+        query: String,
+        candidates: Vec<String>,
+    },
+    /// gpg-agent answered a command with `ERR`
+    Agent {
+        command: String,
+        /// The GnuPG error code, which also encodes the source of the error
+        code: u32,
+        description: String,
+    },
+    /// The SSH agent could not be reached, refused a request or answered something unexpected
+    SshAgent(String),
+    /// An argument was refused before running anything (e.g. an invalid expiry)
+    InvalidArgument(String),
+    /// Reading or writing a file failed
+    Io(std::io::Error),
+}
+
+impl GpgSshError {
+    /// The exit code `main` uses for the error, so wrapper scripts can tell the errors apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GpgSshError::Utf8 { .. } => 3,
+            GpgSshError::Parse(_) => 4,
+            GpgSshError::Fuzzy(_) => 5,
+            GpgSshError::Io(_) => 6,
+            GpgSshError::UnusableKey { .. } => 7,
+            GpgSshError::KeyNotFound(_) => 8,
+            GpgSshError::AmbiguousKey { .. } => 9,
+            GpgSshError::Agent { .. } => 10,
+            GpgSshError::SshAgent(_) => 11,
+            GpgSshError::InvalidArgument(_) => 12,
+            // clap already exits with 2 for an invalid command line
+            GpgSshError::CommandFailed { .. } => 13,
+            GpgSshError::Spawn { .. } => 127,
+        }
+    }
+}
+
+impl Display for GpgSshError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GpgSshError::Spawn { program, source } => write!(
+                f, "Failed to execute `{program}`: {source}"
+            ),
+            GpgSshError::CommandFailed { program, args, stdout, stderr, exit_code } => write!(
+                f, "Failed CMD: {program:?} - Args: {args:?} - Stdout: {stdout:?} - Stderr: {stderr:?} - Exit code: {}",
+                exit_code.map_or("none".to_string(), |code| code.to_string()),
+            ),
+            GpgSshError::Utf8 { program, source } => write!(
+                f, "The output of `{program}` was not valid UTF-8: {source}"
+            ),
+            GpgSshError::Parse(msg) => write!(f, "Failed to parse: {msg}"),
+            GpgSshError::Fuzzy(msg) => write!(f, "Fuzzy finder failed: {msg}"),
+            GpgSshError::UnusableKey { key, reason } => write!(
+                f, "Refusing to use the key {key}, because the {reason} (use --force to use it anyway)"
+            ),
+            GpgSshError::KeyNotFound(query) => write!(f, "Did not find a key matching `{query}`"),
+            GpgSshError::AmbiguousKey { query, candidates } => write!(
+                f, "More than one key matches `{query}`: {}", candidates.join(", ")
+            ),
+            GpgSshError::Agent { command, code, description } => write!(
+                f, "gpg-agent refused `{command}`: {description} (ERR {code})"
+            ),
+            GpgSshError::SshAgent(msg) => write!(f, "SSH agent: {msg}"),
+            GpgSshError::InvalidArgument(msg) => write!(f, "Invalid argument: {msg}"),
+            GpgSshError::Io(err) => write!(f, "IO error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for GpgSshError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GpgSshError::Spawn { source, .. } => Some(source),
+            GpgSshError::Utf8 { source, .. } => Some(source),
+            GpgSshError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for GpgSshError {
+    fn from(err: std::io::Error) -> Self {
+        GpgSshError::Io(err)
+    }
+}
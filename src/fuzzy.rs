@@ -1,25 +1,125 @@
 use std::collections::{HashMap, HashSet};
-use log::{trace};
+use log::trace;
 use skim::{ItemPreview, SkimItemSender, PreviewContext, Skim, SkimItem, SkimItemReceiver};
 use skim::prelude::*;
 
-use crate::gpg::{get_enabled_keygrip, SshKeyInfo, mark_gpg_key_as_ssh_key, unmark_gpg_key_as_ssh_key, gpg_keys};
+use crate::error::{GpgSshError, Result};
+use crate::gpg::colons::format_date;
+use crate::ssh::SshPublicKey;
+use crate::gpg::{ssh_public_keys, get_enabled_keygrip, secret_primary_keys, AuthSubkey, SecretKey, SshKeyInfo, mark_gpg_key_as_ssh_key, unmark_gpg_key_as_ssh_key, gpg_keys, without_public_only, GpgBackend};
 
+/// One auth subkey of a GPG key
 struct MyItem {
     ssh_key_info: SshKeyInfo,
+    auth_subkey: AuthSubkey,
+    /// Marked with `Use-for-ssh` in gpg-agent
+    enabled: bool,
+    /// `None` if gpg could not export the key
+    ssh_public_key: Option<SshPublicKey>,
+}
+
+fn date(timestamp: Option<u64>, none: &str) -> String {
+    timestamp.map_or(none.to_string(), format_date)
+}
+
+impl MyItem {
+    /// The row shown and matched for an auth subkey: enabled marker, algorithm, creation and expiry date,
+    /// the openpgp id, the SSH fingerprint and the UID
+    ///
+    /// Two keys can share a UID, so this is only for the user: items are identified by [`SkimItem::output`].
+    fn row(&self) -> String {
+        let marker = if self.enabled { '*' } else { ' ' };
+        let algorithm = &self.auth_subkey.algorithm;
+        let created = date(self.auth_subkey.created, "-");
+        let expires = date(self.auth_subkey.expires, "never");
+        let hex = self.auth_subkey.openpgp_hex_string();
+        let ssh_fingerprint = self.ssh_public_key.as_ref().map_or("-".to_string(), SshPublicKey::fingerprint_sha256);
+        let name = &self.ssh_key_info.main_name;
+        format!("{marker} {algorithm:<9} {created:<10} {expires:<10} openpgp:0x{hex} {ssh_fingerprint:<50} {name}")
+    }
 }
 
 impl SkimItem for MyItem {
-    fn text(&self) -> Cow<str> {
-        Cow::Borrowed(&self.ssh_key_info.main_name)
+    fn text(&self) -> Cow<'_, str> {
+        Cow::Owned(self.row())
+    }
+
+    /// The keygrip of the auth subkey, which is what the selection maps back to
+    fn output(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self.auth_subkey.keygrip.as_str())
     }
 
     fn preview(&self, _context: PreviewContext) -> ItemPreview {
+        let state = match self.ssh_key_info.unusable_reason(&self.auth_subkey) {
+            Some(reason) => reason.to_string(),
+            None if self.ssh_key_info.is_disabled() => "primary key is disabled".to_string(),
+            None => "valid".to_string(),
+        };
+        let ssh_public_key = self.ssh_public_key.as_ref()
+            .map_or("(the SSH public key could not be exported)".to_string(), SshPublicKey::to_string);
+        let enabled = if self.enabled { "yes" } else { "no" };
         let cert_key_id = self.ssh_key_info.main_key_id.as_str();
-        let auth_keygrip = self.ssh_key_info.auth_keygrip.as_str();
+        let cert_expires = date(self.ssh_key_info.main_expires, "never");
+        let auth_keygrip = self.auth_subkey.keygrip.as_str();
+        let auth_secret = &self.auth_subkey.secret;
+        let user_ids: String = self.ssh_key_info.user_ids.iter()
+            .map(|user_id| format!("  {user_id}\n"))
+            .collect();
+        let subkeys: String = self.ssh_key_info.subkeys.iter()
+            .map(|subkey| {
+                let marker = if subkey.fingerprint == self.auth_subkey.fingerprint { '>' } else { ' ' };
+                format!(
+                    "{marker} {} {:<9} {:<4} {:<10} {}\n",
+                    subkey.fingerprint, subkey.algorithm, subkey.capabilities,
+                    date(subkey.created, "-"), date(subkey.expires, "never"),
+                )
+            })
+            .collect();
         ItemPreview::Text(format!(
-            "Key ID (Cert): {cert_key_id}\n\
-            Keygrip (Auth): {auth_keygrip}"
+            "{ssh_public_key}\n\n\
+            Enabled: {enabled}\n\
+            State: {state}\n\
+            Key ID (Cert): {cert_key_id}\n\
+            Expires (Cert): {cert_expires}\n\
+            Keygrip (Auth): {auth_keygrip}\n\
+            Secret (Auth): {auth_secret}\n\n\
+            User IDs:\n{user_ids}\n\
+            Subkeys:\n{subkeys}"
+        ))
+    }
+}
+
+/// A primary key with its secret key, to add an auth subkey to
+struct SecretKeyItem {
+    secret_key: SecretKey,
+}
+
+impl SkimItem for SecretKeyItem {
+    fn text(&self) -> Cow<'_, str> {
+        let algorithm = &self.secret_key.algorithm;
+        let expires = date(self.secret_key.expires, "never");
+        let fingerprint = &self.secret_key.fingerprint;
+        let name = &self.secret_key.name;
+        Cow::Owned(format!("{algorithm:<9} {expires:<10} {fingerprint} {name}"))
+    }
+
+    /// The fingerprint of the primary key
+    fn output(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self.secret_key.fingerprint.as_str())
+    }
+
+    fn preview(&self, _context: PreviewContext) -> ItemPreview {
+        ItemPreview::Text(format!(
+            "{}\n\n\
+            Fingerprint: {}\n\
+            Algorithm: {}\n\
+            Expires: {}\n\
+            Secret: {}",
+            self.secret_key.name,
+            self.secret_key.fingerprint,
+            self.secret_key.algorithm,
+            date(self.secret_key.expires, "never"),
+            self.secret_key.secret,
         ))
     }
 }
@@ -30,25 +130,56 @@ struct MySelector {
 
 impl Selector for MySelector {
     fn should_select(&self, _index: usize, item: &dyn SkimItem) -> bool {
-        self.toggl_keygrip.contains(item.text().to_string().as_str())
+        self.toggl_keygrip.contains(item.output().as_ref())
     }
 }
 
-pub(crate) fn fzf_set(ssh_keys_info: Vec<SshKeyInfo>) {
-    let keygrip = get_enabled_keygrip();
+/// Sends the row of an auth subkey to skim, `ssh_public_keys` comes from [`ssh_public_keys`]
+fn send_item(
+    ssh_public_keys: &HashMap<String, Result<SshPublicKey>>,
+    tx_item: &SkimItemSender,
+    ssh_key_info: &SshKeyInfo,
+    auth_subkey: &AuthSubkey,
+    enabled: bool,
+) -> Result<()> {
+    tx_item.send(Arc::new(MyItem {
+        ssh_key_info: ssh_key_info.clone(),
+        auth_subkey: auth_subkey.clone(),
+        enabled,
+        ssh_public_key: ssh_public_keys.get(&auth_subkey.keygrip).and_then(|ssh_public_key| ssh_public_key.as_ref().ok()).cloned(),
+    })).map_err(|err| GpgSshError::Fuzzy(err.to_string()))
+}
+
+fn skim_options(multi: bool) -> Result<SkimOptions<'static>> {
+    SkimOptionsBuilder::default()
+        .height(Some("50%"))
+        .multi(multi)
+        .preview(Some("")) // preview should be specified to enable preview window
+        .no_clear_start(true)
+        .build()
+        .map_err(|err| GpgSshError::Fuzzy(err.to_string()))
+}
+
+/// Lets the user pick, with skim, which of the keys gpg-agent should offer to the SSH client
+///
+/// The keys already enabled are preselected. Keys selected are marked and keys unselected are unmarked.
+/// Newly selected keys which are expired or revoked are refused, unless `force` is set.
+pub fn fzf_set(backend: &dyn GpgBackend, ssh_keys_info: Vec<SshKeyInfo>, force: bool) -> Result<()> {
+    let keygrip = get_enabled_keygrip(backend)?;
+    let ssh_public_keys = ssh_public_keys(backend, &ssh_keys_info)?;
 
     let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
 
-    let selected = HashSet::from_iter(
-        ssh_keys_info.iter().filter(
-            |ssh_key_info| {
-                tx_item.send(Arc::new(MyItem {
-                    ssh_key_info: (*ssh_key_info).clone(),
-                })).unwrap();
-                keygrip.contains(ssh_key_info.auth_keygrip.as_str())
+    let mut selected = HashSet::new();
+    for ssh_key_info in &ssh_keys_info {
+        for auth_subkey in &ssh_key_info.auth_subkeys {
+            let enabled = keygrip.contains(auth_subkey.keygrip.as_str());
+            send_item(&ssh_public_keys, &tx_item, ssh_key_info, auth_subkey, enabled)?;
+            if enabled {
+                selected.insert(auth_subkey.keygrip.clone());
             }
-        ).map(|ssh_keys_info| ssh_keys_info.main_name.clone())
-    );
+        }
+    }
 
     drop(tx_item); // so that skim could know when to stop waiting for more items.
 
@@ -57,14 +188,7 @@ pub(crate) fn fzf_set(ssh_keys_info: Vec<SshKeyInfo>) {
     });
 
 
-    let mut options = SkimOptionsBuilder::default()
-        .height(Some("50%"))
-        .multi(true)
-        .preview(Some("")) // preview should be specified to enable preview window
-        .no_clear_start(true)
-        .multi(true)
-        .build()
-        .unwrap();
+    let mut options = skim_options(true)?;
 
     options.selector = Some(my_selector);
 
@@ -79,9 +203,9 @@ pub(crate) fn fzf_set(ssh_keys_info: Vec<SshKeyInfo>) {
         }).unwrap_or(None);
 
     if let Some(selected_items) = selected_items {
-        let lookup: HashMap<String, &SshKeyInfo> = HashMap::from_iter(
-            ssh_keys_info.iter().map(|info| {
-                (info.main_name.clone(), info)
+        let lookup: HashMap<String, (&SshKeyInfo, &AuthSubkey)> = HashMap::from_iter(
+            ssh_keys_info.iter().flat_map(|info| {
+                info.auth_subkeys.iter().map(move |auth_subkey| (auth_subkey.keygrip.clone(), (info, auth_subkey)))
             }
         ));
 
@@ -89,46 +213,50 @@ pub(crate) fn fzf_set(ssh_keys_info: Vec<SshKeyInfo>) {
         let mut missing_keygrip = Vec::new();
         
         for selected_item in selected_items {
-            let item= lookup.get(&selected_item.text().to_string()).unwrap();
+            let (info, item) = lookup.get(selected_item.output().as_ref()).ok_or_else(|| GpgSshError::Fuzzy(
+                format!("The selected item `{}` is not a known key", selected_item.text())
+            ))?;
             trace!("Select item: `{:?}`", item);
-            if ! keygrip.contains(&item.auth_keygrip) { missing_keygrip.push(item.auth_keygrip.as_str()) }
-            keygrip_selected.insert(item.auth_keygrip.clone());
+            if ! keygrip.contains(&item.keygrip) {
+                info.ensure_usable(item, force)?;
+                missing_keygrip.push(item.keygrip.as_str())
+            }
+            keygrip_selected.insert(item.keygrip.clone());
         }
-        mark_gpg_key_as_ssh_key(&missing_keygrip);
+        mark_gpg_key_as_ssh_key(backend, &missing_keygrip)?;
         
         let remove_keygrip = Vec::from_iter(&keygrip - &keygrip_selected);
-        unmark_gpg_key_as_ssh_key(&remove_keygrip);
+        unmark_gpg_key_as_ssh_key(backend, &remove_keygrip)?;
     }
+
+    Ok(())
 }
 
 
-pub(crate) fn fzf_copy_id() -> Vec<String> {
-    let gpg_keys = gpg_keys(None);
-    let enabled_keygrip = get_enabled_keygrip();
+/// Lets the user pick, with skim, among the auth subkeys enabled for SSH
+///
+/// Returns the key ids (see [`AuthSubkey::export_key_id`]) of the selected subkeys.
+/// Selected keys which are expired or revoked are refused, unless `force` is set.
+pub fn fzf_copy_id(backend: &dyn GpgBackend, force: bool) -> Result<Vec<String>> {
+    let gpg_keys = without_public_only(gpg_keys(backend, None)?);
+    let enabled_keygrip = get_enabled_keygrip(backend)?;
+    let ssh_public_keys = ssh_public_keys(backend, &gpg_keys)?;
 
     let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
 
-    let mut key_info_by_name: HashMap<String, SshKeyInfo> = HashMap::new();
-    // let mut key_info_by_keygrip = HashMap::new();
+    let mut key_info_by_keygrip: HashMap<String, (SshKeyInfo, AuthSubkey)> = HashMap::new();
     for info in gpg_keys {
-        key_info_by_name.insert(info.main_name.clone(), info.clone());
-        // key_info_by_keygrip.insert(info.keygrip, &info);
+        for auth_subkey in &info.auth_subkeys {
+            key_info_by_keygrip.insert(auth_subkey.keygrip.clone(), (info.clone(), auth_subkey.clone()));
 
-        if enabled_keygrip.contains(&info.auth_keygrip) {
-            tx_item.send(Arc::new(MyItem {
-                ssh_key_info: info.clone(),
-            })).unwrap();
+            if enabled_keygrip.contains(&auth_subkey.keygrip) {
+                send_item(&ssh_public_keys, &tx_item, &info, auth_subkey, true)?;
+            }
         }
     }
+    drop(tx_item);
 
-    let options = SkimOptionsBuilder::default()
-        .height(Some("50%"))
-        .multi(true)
-        .preview(Some("")) // preview should be specified to enable preview window
-        .no_clear_start(true)
-        .multi(true)
-        .build()
-        .unwrap();
+    let options = skim_options(true)?;
 
     let selected_items = Skim::run_with(&options, Some(rx_item)).map(
         |out| {
@@ -138,11 +266,43 @@ pub(crate) fn fzf_copy_id() -> Vec<String> {
             } else {
                 out.selected_items
             }
-        }).unwrap_or(Vec::new());
+        }).unwrap_or_default();
+
+    let mut key_ids = Vec::new();
+    for item in selected_items {
+        if let Some((info, auth_subkey)) = key_info_by_keygrip.get(item.output().as_ref()) {
+            info.ensure_usable(auth_subkey, force)?;
+            key_ids.push(auth_subkey.export_key_id());
+        }
+    }
+
+    Ok(key_ids)
+}
+
+
+/// Lets the user pick, with skim, one of the primary keys with a secret key, e.g. to add an auth subkey to
+///
+/// Returns the fingerprint of the selected key, `None` if the user aborted.
+pub fn fzf_secret_key(backend: &dyn GpgBackend) -> Result<Option<String>> {
+    let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
+
+    for secret_key in secret_primary_keys(backend)? {
+        tx_item.send(Arc::new(SecretKeyItem { secret_key }))
+            .map_err(|err| GpgSshError::Fuzzy(err.to_string()))?;
+    }
+    drop(tx_item);
+
+    let options = skim_options(false)?;
+
+    let selected_item = Skim::run_with(&options, Some(rx_item)).and_then(
+        |out| {
+            if out.is_abort {
+                trace!("Fuzzer was aborted");
+                None
+            } else {
+                out.selected_items.into_iter().next()
+            }
+        });
 
-    selected_items.iter().filter_map(|item| {
-        key_info_by_name.get(
-            item.text().to_string().as_str()
-        ).map(|info| (*info).clone().main_key_id.to_string())
-    }).collect()
+    Ok(selected_item.map(|item| item.output().to_string()))
 }
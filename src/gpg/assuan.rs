@@ -0,0 +1,259 @@
+//! Client for the Assuan protocol, which gpg-agent speaks on its socket
+//!
+//! Every command is answered by any number of `S` (status), `D` (data), `INQUIRE` and `#` (comment)
+//! lines, ended by either `OK` or `ERR <code> <description>`.
+//! The protocol is described in the Assuan manual of GnuPG.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use log::trace;
+use crate::error::{GpgSshError, Result};
+
+/// Lines may not be longer than this, including the line feed
+const LINE_LENGTH: usize = 1000;
+
+/// An `S <keyword> <args>` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusLine {
+    pub keyword: String,
+    /// The rest of the line, percent-unescaped
+    pub args: String,
+}
+
+/// What the agent answered to a command which succeeded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AgentResponse {
+    /// The `S` lines, in the order they were sent
+    pub status: Vec<StatusLine>,
+    /// The content of every `D` line, percent-unescaped and concatenated
+    pub data: Vec<u8>,
+    /// The text after `OK`, usually empty
+    pub ok: String,
+}
+
+impl AgentResponse {
+    /// The arguments of the status lines with the given keyword
+    pub fn status_args<'a>(&'a self, keyword: &'a str) -> impl Iterator<Item = &'a str> {
+        self.status.iter()
+            .filter(move |status| status.keyword == keyword)
+            .map(|status| status.args.as_str())
+    }
+}
+
+/// Percent-escapes `%`, CR and LF, the characters which cannot be sent as they are
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        match byte {
+            b'%' | b'\r' | b'\n' => escaped.extend(format!("%{byte:02X}").as_bytes()),
+            _ => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+/// Replaces every `%XX` with the byte it stands for, a `%` not followed by two hex digits is kept
+pub fn unescape(bytes: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 3;
+            },
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+
+    unescaped
+}
+
+fn unescape_string(text: &str) -> String {
+    String::from_utf8_lossy(&unescape(text.as_bytes())).to_string()
+}
+
+fn parse_err(command: &str, args: &str) -> GpgSshError {
+    let (code, description) = args.split_once(' ').unwrap_or((args, ""));
+    GpgSshError::Agent {
+        command: command.to_string(),
+        code: code.parse().unwrap_or(0),
+        description: unescape_string(description),
+    }
+}
+
+/// Sends `data` as `D` lines followed by `END`, as the answer to an `INQUIRE`
+fn send_data(writer: &mut dyn Write, data: &[u8]) -> Result<()> {
+    // "D " and the line feed take 3 bytes, and an escape sequence must not be split over two lines
+    for chunk in data.chunks((LINE_LENGTH - 3) / 3) {
+        writer.write_all(b"D ")?;
+        writer.write_all(&escape(chunk))?;
+        writer.write_all(b"\n")?;
+    }
+    writeln!(writer, "END")?;
+    Ok(())
+}
+
+/// Reads the lines answering `command` up to the final `OK` or `ERR`
+///
+/// Each `INQUIRE <keyword>` is answered with the data `inquire` returns for the keyword,
+/// or cancelled (`CAN`) if it returns `None`. An `ERR` line becomes a [`GpgSshError::Agent`].
+pub fn read_response(
+    reader: &mut dyn BufRead,
+    writer: &mut dyn Write,
+    command: &str,
+    inquire: &mut dyn FnMut(&str) -> Option<Vec<u8>>,
+) -> Result<AgentResponse> {
+    let mut response = AgentResponse::default();
+
+    loop {
+        let mut raw_line = Vec::new();
+        if reader.read_until(b'\n', &mut raw_line)? == 0 {
+            return Err(GpgSshError::Parse(format!("gpg-agent closed the connection while answering `{command}`")));
+        }
+        while raw_line.last().is_some_and(|byte| matches!(byte, b'\r' | b'\n')) {
+            raw_line.pop();
+        }
+        // Data may be binary, the other lines are text
+        if let Some(data) = raw_line.strip_prefix(b"D ") {
+            response.data.extend(unescape(data));
+            continue;
+        }
+        let line = String::from_utf8_lossy(&raw_line);
+        let line = line.as_ref();
+        trace!("gpg-agent: {line}");
+
+        let (verb, args) = line.split_once(' ').unwrap_or((line, ""));
+        match verb {
+            "OK" => {
+                response.ok = args.to_string();
+                return Ok(response);
+            },
+            "ERR" => return Err(parse_err(command, args)),
+            "S" => {
+                let (keyword, args) = args.split_once(' ').unwrap_or((args, ""));
+                response.status.push(StatusLine { keyword: keyword.to_string(), args: unescape_string(args) });
+            },
+            "INQUIRE" => {
+                let keyword = args.split(' ').next().unwrap_or_default();
+                match inquire(keyword) {
+                    Some(data) => send_data(writer, &data)?,
+                    None => writeln!(writer, "CAN")?,
+                }
+                writer.flush()?;
+            },
+            // A comment, or a `D` line without data
+            "#" | "D" | "" => {},
+            _ => return Err(GpgSshError::Parse(format!("Unexpected line from gpg-agent: {line}"))),
+        }
+    }
+}
+
+/// A connection to gpg-agent
+#[derive(Debug)]
+pub struct AssuanClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl AssuanClient {
+    /// Connects to the socket and reads the greeting of the agent
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket)?;
+        let mut client = AssuanClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        read_response(&mut client.reader, &mut client.writer, "(greeting)", &mut |_| None)?;
+        Ok(client)
+    }
+
+    /// Sends the command and returns the response, inquiries of the agent are cancelled
+    pub fn transact(&mut self, command: &str) -> Result<AgentResponse> {
+        self.transact_with_inquire(command, &mut |_| None)
+    }
+
+    /// Sends the command and returns the response, inquiries are answered with `inquire`
+    pub fn transact_with_inquire(
+        &mut self,
+        command: &str,
+        inquire: &mut dyn FnMut(&str) -> Option<Vec<u8>>,
+    ) -> Result<AgentResponse> {
+        if command.len() >= LINE_LENGTH || command.contains(['\r', '\n']) {
+            return Err(GpgSshError::Parse(format!("Not a valid Assuan command: {command:?}")));
+        }
+
+        trace!("to gpg-agent: {command}");
+        writeln!(self.writer, "{command}")?;
+        self.writer.flush()?;
+        read_response(&mut self.reader, &mut self.writer, command, inquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(lines: &str) -> (Result<AgentResponse>, String) {
+        let mut written = Vec::new();
+        let result = read_response(&mut lines.as_bytes(), &mut written, "TEST", &mut |keyword| {
+            (keyword == "PASSPHRASE").then(|| b"100% secret".to_vec())
+        });
+        (result, String::from_utf8(written).unwrap())
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        assert_eq!(escape("50%\r\nöк".as_bytes()), "50%25%0D%0Aöк".as_bytes());
+        assert_eq!(unescape("50%25%0D%0Aöк".as_bytes()), "50%\r\nöк".as_bytes());
+        assert_eq!(unescape(b"%zz and 100%"), b"%zz and 100%");
+    }
+
+    #[test]
+    fn reads_status_and_data() {
+        let (result, written) = response("# comment\nS KEYINFO AAAA D - - - P - - -\nD hello%0A\nD world\nOK done\n");
+
+        let response = result.unwrap();
+        assert_eq!(response.status_args("KEYINFO").collect::<Vec<_>>(), vec!["AAAA D - - - P - - -"]);
+        assert_eq!(response.data, b"hello\nworld");
+        assert_eq!(response.ok, "done");
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn err_line_becomes_agent_error() {
+        let (result, _) = response("ERR 67108881 Not implemented <GPG Agent>\n");
+
+        match result {
+            Err(GpgSshError::Agent { command, code, description }) => {
+                assert_eq!(command, "TEST");
+                assert_eq!(code, 67108881);
+                assert_eq!(description, "Not implemented <GPG Agent>");
+            },
+            result => panic!("expected an agent error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn inquiries_are_answered_or_cancelled() {
+        let (result, written) = response("INQUIRE PASSPHRASE\nINQUIRE PINENTRY_LAUNCHED 1234\nOK\n");
+
+        assert!(result.is_ok());
+        assert_eq!(written, "D 100%25 secret\nEND\nCAN\n");
+    }
+
+    #[test]
+    fn missing_ok_is_an_error() {
+        assert!(matches!(response("S PROGRESS\n").0, Err(GpgSshError::Parse(_))));
+    }
+}
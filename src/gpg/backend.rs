@@ -0,0 +1,219 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use log::debug;
+use crate::error::{GpgSshError, Result};
+use super::assuan::{unescape, AgentResponse, AssuanClient};
+use super::helper_fn::{run_command, run_command_with_input, stdout_to_string};
+use super::passphrase::Passphrase;
+
+/// The calls made to GnuPG, so the logic on top can run against something else than the real `gpg`
+pub trait GpgBackend {
+    /// `gpg --list-keys --with-keygrip --with-colons [key_ids]`
+    fn list_keys(&self, key_ids: &[String]) -> Result<String>;
+
+    /// `gpg --list-secret-keys --with-keygrip --with-colons [key_ids]`
+    fn list_secret_keys(&self, key_ids: &[String]) -> Result<String>;
+
+    /// `gpg --with-colons --quick-generate-key <user_id> <algo> <usage> <expire>`, see [`Passphrase`] for how
+    /// the passphrase is passed on. Returns the colon listing of the new key
+    fn quick_generate_key(&self, user_id: &str, algo: &str, usage: &str, expire: &str, passphrase: &Passphrase) -> Result<String>;
+
+    /// `gpg --quick-add-key <fingerprint> <algo> <usage> <expire>`, see [`Passphrase`] for how
+    /// the passphrase is passed on
+    fn quick_add_key(&self, fingerprint: &str, algo: &str, usage: &str, expire: &str, passphrase: &Passphrase) -> Result<()>;
+
+    /// `gpg --batch --yes --delete-secret-and-public-key <fingerprint>`, deletes the key without asking
+    fn delete_key(&self, fingerprint: &str) -> Result<()>;
+
+    /// `gpg --export-ssh-key <key_id>`
+    fn export_ssh_key(&self, key_id: &str) -> Result<String>;
+
+    /// `gpg --export <key_ids>`, the public keys as binary OpenPGP packets
+    fn export_keys(&self, key_ids: &[String]) -> Result<Vec<u8>>;
+
+    /// One of the directories or sockets of GnuPG (`gpgconf --list-dirs <name>`), e.g. `agent-ssh-socket`
+    fn list_dir(&self, name: &str) -> Result<PathBuf>;
+
+    /// Sends an Assuan command to gpg-agent and returns its response, an `ERR` is returned as
+    /// [`GpgSshError::Agent`](crate::GpgSshError::Agent)
+    fn agent_command(&self, command: &str) -> Result<AgentResponse>;
+}
+
+/// Runs `gpg` as a subprocess and talks to gpg-agent over its socket
+///
+/// The connection to gpg-agent is opened on the first agent command and then reused, until a command fails
+/// with something else than an `ERR` of gpg-agent.
+#[derive(Debug, Default)]
+pub struct SubprocessBackend {
+    agent: RefCell<Option<AssuanClient>>,
+}
+
+impl SubprocessBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn gpg(&self) -> Command {
+        let mut command = Command::new("gpg");
+        command.stdin(Stdio::null()).stderr(Stdio::piped()).stdout(Stdio::piped());
+        command
+    }
+
+    /// `gpg` reading the passphrase from stdin instead of asking with pinentry, unless it is [`Passphrase::Pinentry`]
+    fn gpg_with_passphrase(&self, passphrase: &Passphrase) -> Command {
+        let mut command = self.gpg();
+        if passphrase.stdin().is_some() {
+            command.args(["--batch", "--pinentry-mode", "loopback", "--passphrase-fd", "0"]);
+        }
+        command
+    }
+
+    /// Runs the command from [`Self::gpg_with_passphrase`], the passphrase is written to stdin
+    fn run_with_passphrase(&self, command: &mut Command, passphrase: &Passphrase) -> Result<Output> {
+        match passphrase.stdin() {
+            Some(input) => run_command_with_input(command, &input),
+            None => run_command(command),
+        }
+    }
+
+    fn gpgconf(&self) -> Command {
+        let mut command = Command::new("gpgconf");
+        command.stdin(Stdio::null()).stderr(Stdio::piped()).stdout(Stdio::piped());
+        command
+    }
+
+    /// Connects to gpg-agent, starting it (`gpgconf --launch gpg-agent`) if it is not running
+    fn connect_agent(&self) -> Result<AssuanClient> {
+        let socket = self.list_dir("agent-socket")?;
+        AssuanClient::connect(&socket).or_else(|err| {
+            debug!("Could not connect to gpg-agent at {}, launching it: {err}", socket.display());
+            let mut command = self.gpgconf();
+            command.arg("--launch").arg("gpg-agent");
+            run_command(&mut command)?;
+            AssuanClient::connect(&socket)
+        })
+    }
+}
+
+impl GpgBackend for SubprocessBackend {
+    fn list_keys(&self, key_ids: &[String]) -> Result<String> {
+        let mut command = self.gpg();
+        command
+            .arg("--list-keys")
+            .arg("--with-keygrip")
+            .arg("--with-colons")
+            .args(key_ids);
+
+        let output = run_command(&mut command)?;
+        stdout_to_string(&command, output)
+    }
+
+    fn list_secret_keys(&self, key_ids: &[String]) -> Result<String> {
+        let mut command = self.gpg();
+        command
+            .arg("--list-secret-keys")
+            .arg("--with-keygrip")
+            .arg("--with-colons")
+            .args(key_ids);
+
+        let output = run_command(&mut command)?;
+        stdout_to_string(&command, output)
+    }
+
+    fn quick_generate_key(&self, user_id: &str, algo: &str, usage: &str, expire: &str, passphrase: &Passphrase) -> Result<String> {
+        let mut command = self.gpg_with_passphrase(passphrase);
+        if passphrase.stdin().is_some() {
+            // With --batch gpg does not list the new key, only the KEY_CREATED status tells its fingerprint
+            command.arg("--status-fd").arg("1");
+        }
+        command
+            .arg("--with-colons")
+            .arg("--quick-generate-key")
+            .arg(user_id)
+            .arg(algo)
+            .arg(usage)
+            .arg(expire);
+
+        let output = self.run_with_passphrase(&mut command, passphrase)?;
+        let stdout = stdout_to_string(&command, output)?;
+
+        // `[GNUPG:] KEY_CREATED <type> <fingerprint>`
+        let created = stdout.lines()
+            .filter_map(|line| line.strip_prefix("[GNUPG:] KEY_CREATED "))
+            .find_map(|args| args.split(' ').nth(1));
+        match created {
+            Some(fingerprint) => self.list_keys(&[fingerprint.to_string()]),
+            None => Ok(stdout),
+        }
+    }
+
+    fn quick_add_key(&self, fingerprint: &str, algo: &str, usage: &str, expire: &str, passphrase: &Passphrase) -> Result<()> {
+        let mut command = self.gpg_with_passphrase(passphrase);
+        command
+            .arg("--quick-add-key")
+            .arg(fingerprint)
+            .arg(algo)
+            .arg(usage)
+            .arg(expire);
+
+        self.run_with_passphrase(&mut command, passphrase)?;
+        Ok(())
+    }
+
+    fn delete_key(&self, fingerprint: &str) -> Result<()> {
+        let mut command = self.gpg();
+        command
+            .arg("--batch")
+            .arg("--yes")
+            .arg("--delete-secret-and-public-key")
+            .arg(fingerprint);
+
+        run_command(&mut command)?;
+        Ok(())
+    }
+
+    fn export_ssh_key(&self, key_id: &str) -> Result<String> {
+        let mut command = self.gpg();
+        command
+            .arg("--export-ssh-key")
+            .arg(key_id);
+
+        let output = run_command(&mut command)?;
+        stdout_to_string(&command, output)
+    }
+
+    fn export_keys(&self, key_ids: &[String]) -> Result<Vec<u8>> {
+        let mut command = self.gpg();
+        command
+            .arg("--export")
+            .args(key_ids);
+
+        Ok(run_command(&mut command)?.stdout)
+    }
+
+    fn list_dir(&self, name: &str) -> Result<PathBuf> {
+        let mut command = self.gpgconf();
+        command.arg("--list-dirs").arg(name);
+
+        let output = run_command(&mut command)?;
+        let stdout = stdout_to_string(&command, output)?;
+        // gpgconf percent-escapes the directories, e.g. a `:` as `%3a`
+        Ok(PathBuf::from(String::from_utf8_lossy(&unescape(stdout.trim().as_bytes())).to_string()))
+    }
+
+    fn agent_command(&self, command: &str) -> Result<AgentResponse> {
+        let mut agent = self.agent.borrow_mut();
+        let mut client = match agent.take() {
+            Some(client) => client,
+            None => self.connect_agent()?,
+        };
+        let response = client.transact(command);
+        // The connection is still good after an `ERR`, after anything else (e.g. gpg-agent was restarted)
+        // it is dropped and the next command connects again
+        if matches!(response, Ok(_) | Err(GpgSshError::Agent { .. })) {
+            *agent = Some(client);
+        }
+        response
+    }
+}
@@ -0,0 +1,614 @@
+//! Parser for the `--with-colons` output of gpg
+//!
+//! The format is described in `doc/DETAILS` of the GnuPG source code.
+
+use std::fmt::{Display, Formatter};
+use log::debug;
+use serde::Serialize;
+use crate::error::{GpgSshError, Result};
+
+/// The validity of a key or user id (field 2), the same letters are used for the ownertrust (field 9)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Validity {
+    /// `o`, `-` or empty: Unknown (e.g. a new key)
+    #[default]
+    Unknown,
+    /// `i`: The key is invalid (e.g. due to a missing self-signature)
+    Invalid,
+    /// `d`: The key has been disabled
+    Disabled,
+    /// `r`: The key has been revoked
+    Revoked,
+    /// `e`: The key has expired
+    Expired,
+    /// `q`: Undefined validity
+    Undefined,
+    /// `n`: The key is not valid
+    Never,
+    /// `m`: The key is marginal valid
+    Marginal,
+    /// `f`: The key is fully valid
+    Full,
+    /// `u`: The key is ultimately valid (usually one of your own keys)
+    Ultimate,
+    /// `w`: The key has a well known private part
+    WellKnown,
+    /// `s`: The key has special validity
+    Special,
+}
+
+impl Validity {
+    /// Letters newer versions of gpg might add are taken as [`Validity::Unknown`], so one record
+    /// does not break the whole listing
+    fn parse(field: &str) -> Self {
+        match field {
+            "" | "o" | "-" => Validity::Unknown,
+            "i" => Validity::Invalid,
+            "d" => Validity::Disabled,
+            "r" => Validity::Revoked,
+            "e" => Validity::Expired,
+            "q" => Validity::Undefined,
+            "n" => Validity::Never,
+            "m" => Validity::Marginal,
+            "f" => Validity::Full,
+            "u" => Validity::Ultimate,
+            "w" => Validity::WellKnown,
+            "s" => Validity::Special,
+            _ => {
+                debug!("Unknown validity `{field}`, taken as unknown");
+                Validity::Unknown
+            },
+        }
+    }
+}
+
+/// The kind of key record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    /// `pub`: Public primary key
+    Public,
+    /// `sec`: Secret primary key
+    Secret,
+    /// `sub`: Public subkey
+    PublicSub,
+    /// `ssb`: Secret subkey
+    SecretSub,
+}
+
+impl KeyKind {
+    /// Whether the record is a subkey (`sub` or `ssb`)
+    pub fn is_subkey(&self) -> bool {
+        matches!(self, KeyKind::PublicSub | KeyKind::SecretSub)
+    }
+}
+
+/// The capabilities of a key (field 12)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    pub encrypt: bool,
+    pub sign: bool,
+    pub certify: bool,
+    pub authenticate: bool,
+}
+
+impl Display for Capabilities {
+    /// The lowercase letters gpg uses, e.g. `sa` for a key which can sign and authenticate
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let letters = [(self.encrypt, 'e'), (self.sign, 's'), (self.certify, 'c'), (self.authenticate, 'a')];
+        for (capable, letter) in letters {
+            if capable {
+                write!(f, "{letter}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A `pub`, `sec`, `sub` or `ssb` record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRecord {
+    pub kind: KeyKind,
+    pub validity: Validity,
+    /// The length of the key in bits
+    pub key_length: u32,
+    /// The OpenPGP public key algorithm id (e.g. 1 for RSA, 22 for EdDSA)
+    pub algorithm: u8,
+    /// The long key id (the last 16 hex digits of the fingerprint)
+    pub key_id: String,
+    /// Creation time in seconds since epoch
+    pub created: Option<u64>,
+    /// Expiry time in seconds since epoch, `None` if the key does not expire
+    pub expires: Option<u64>,
+    pub ownertrust: Validity,
+    /// The lowercase letters: what the key itself can be used for
+    pub capabilities: Capabilities,
+    /// The uppercase letters (only on primary keys): what the key as a whole can be used for
+    pub overall_capabilities: Capabilities,
+    /// The key has been disabled (`D` in the capabilities)
+    pub disabled: bool,
+    /// For secret keys: the serial number of the card holding the key,
+    /// `+` if the secret key is available or `#` if it is only a stub
+    pub serial_number: Option<String>,
+    /// The name of the curve, for ECC keys
+    pub curve: Option<String>,
+}
+
+impl KeyRecord {
+    /// The algorithm the way gpg shows it, e.g. `ed25519`, `nistp256` or `rsa3072`
+    pub fn algorithm_name(&self) -> String {
+        if let Some(curve) = &self.curve {
+            return curve.clone();
+        }
+
+        let name = match self.algorithm {
+            1..=3 => "rsa",
+            16 | 20 => "elg",
+            17 => "dsa",
+            algorithm => return format!("unknown{algorithm}"),
+        };
+        format!("{name}{}", self.key_length)
+    }
+}
+
+/// A `uid` record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UidRecord {
+    pub validity: Validity,
+    /// Creation time in seconds since epoch
+    pub created: Option<u64>,
+    /// Expiry time in seconds since epoch, `None` if the user id does not expire
+    pub expires: Option<u64>,
+    /// The hash of the user id
+    pub hash: String,
+    /// The user id, e.g. `Alice (SSH Key) <alice@example.com>`
+    pub user_id: String,
+}
+
+/// A `tru` record, the trust database information
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustRecord {
+    /// The trust database is out of date and should be checked
+    pub stale: bool,
+    /// 0 for the classic trust model, 1 for the PGP trust model
+    pub trust_model: u8,
+    /// Creation time in seconds since epoch
+    pub created: Option<u64>,
+    /// Expiry time in seconds since epoch
+    pub expires: Option<u64>,
+    pub marginals_needed: u32,
+    pub completes_needed: u32,
+    pub max_cert_depth: u32,
+}
+
+/// One line of the `--with-colons` output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Key(KeyRecord),
+    Uid(UidRecord),
+    /// `fpr`: The fingerprint of the preceding key
+    Fingerprint(String),
+    /// `grp`: The keygrip of the preceding key
+    Keygrip(String),
+    Trust(TrustRecord),
+    /// Records not needed here (e.g. `sig`, `rev`, `uat`), by their type
+    Other(String),
+}
+
+struct Fields<'a> {
+    line: &'a str,
+    fields: Vec<&'a str>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(line: &'a str) -> Self {
+        Fields { line, fields: line.split(':').collect() }
+    }
+
+    /// The field with the 0-based index, gpg leaves out empty fields at the end of a line
+    fn get(&self, index: usize) -> &'a str {
+        self.fields.get(index).copied().unwrap_or_default()
+    }
+
+    fn optional(&self, index: usize) -> Option<String> {
+        Some(self.get(index)).filter(|field| !field.is_empty()).map(str::to_string)
+    }
+
+    fn number<T: std::str::FromStr + Default>(&self, index: usize) -> Result<T> {
+        let field = self.get(index);
+        if field.is_empty() { return Ok(T::default()) }
+        field.parse().map_err(|_| GpgSshError::Parse(
+            format!("Expected a number in field {} of the line: {}", index + 1, self.line)
+        ))
+    }
+
+    /// A timestamp, where empty and 0 means no timestamp
+    fn timestamp(&self, index: usize) -> Result<Option<u64>> {
+        Ok(Some(self.number(index)?).filter(|timestamp| *timestamp != 0))
+    }
+
+    fn validity(&self, index: usize) -> Validity {
+        Validity::parse(self.get(index))
+    }
+}
+
+/// Formats a timestamp (seconds since epoch) as an UTC date, e.g. `2024-09-21`
+pub fn format_date(timestamp: u64) -> String {
+    // Howard Hinnant's `civil_from_days`
+    let days = (timestamp / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Decodes the C-style escapes gpg uses for the user id field (e.g. `\x3a` for `:`)
+///
+/// The unescaped bytes are read as UTF-8, invalid sequences are replaced with `U+FFFD`.
+pub fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] != b'\\' || index + 1 == bytes.len() {
+            unescaped.push(bytes[index]);
+            index += 1;
+            continue
+        }
+
+        let (byte, length) = match bytes[index + 1] {
+            b'n' => (Some(b'\n'), 2),
+            b'r' => (Some(b'\r'), 2),
+            b't' => (Some(b'\t'), 2),
+            b'f' => (Some(0x0c), 2),
+            b'v' => (Some(0x0b), 2),
+            b'b' => (Some(0x08), 2),
+            b'0' => (Some(0x00), 2),
+            b'\\' => (Some(b'\\'), 2),
+            b'x' => (
+                field.get(index + 2..index + 4).and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                4,
+            ),
+            _ => (None, 1),
+        };
+
+        match byte {
+            Some(byte) => {
+                unescaped.push(byte);
+                index += length;
+            },
+            None => {
+                unescaped.push(b'\\');
+                index += 1;
+            },
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+/// Escapes a value the way gpg does for the user id field, the reverse of [`unescape`]
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for char in value.chars() {
+        match char {
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\x0c' => escaped.push_str("\\f"),
+            '\x0b' => escaped.push_str("\\v"),
+            '\x08' => escaped.push_str("\\b"),
+            '\0' => escaped.push_str("\\0"),
+            ':' | '\\' | '\x7f' | '\x00'..='\x1f' => escaped.push_str(&format!("\\x{:02x}", char as u8)),
+            char => escaped.push(char),
+        }
+    }
+
+    escaped
+}
+
+fn parse_capabilities(field: &str) -> (Capabilities, Capabilities, bool) {
+    let mut capabilities = Capabilities::default();
+    let mut overall_capabilities = Capabilities::default();
+    let mut disabled = false;
+
+    for char in field.chars() {
+        match char {
+            'e' => capabilities.encrypt = true,
+            's' => capabilities.sign = true,
+            'c' => capabilities.certify = true,
+            'a' => capabilities.authenticate = true,
+            'E' => overall_capabilities.encrypt = true,
+            'S' => overall_capabilities.sign = true,
+            'C' => overall_capabilities.certify = true,
+            'A' => overall_capabilities.authenticate = true,
+            'D' => disabled = true,
+            _ => {},
+        }
+    }
+
+    (capabilities, overall_capabilities, disabled)
+}
+
+fn parse_key(kind: KeyKind, fields: &Fields) -> Result<KeyRecord> {
+    let (capabilities, overall_capabilities, disabled) = parse_capabilities(fields.get(11));
+
+    Ok(KeyRecord {
+        kind,
+        validity: fields.validity(1),
+        key_length: fields.number(2)?,
+        algorithm: fields.number(3)?,
+        key_id: fields.get(4).to_string(),
+        created: fields.timestamp(5)?,
+        expires: fields.timestamp(6)?,
+        ownertrust: fields.validity(8),
+        capabilities,
+        overall_capabilities,
+        disabled,
+        serial_number: fields.optional(14),
+        curve: fields.optional(16),
+    })
+}
+
+/// Parses a single line of the `--with-colons` output
+pub fn parse_line(line: &str) -> Result<Record> {
+    let fields = Fields::new(line);
+
+    Ok(match fields.get(0) {
+        "pub" => Record::Key(parse_key(KeyKind::Public, &fields)?),
+        "sec" => Record::Key(parse_key(KeyKind::Secret, &fields)?),
+        "sub" => Record::Key(parse_key(KeyKind::PublicSub, &fields)?),
+        "ssb" => Record::Key(parse_key(KeyKind::SecretSub, &fields)?),
+        "uid" => Record::Uid(UidRecord {
+            validity: fields.validity(1),
+            created: fields.timestamp(5)?,
+            expires: fields.timestamp(6)?,
+            hash: fields.get(7).to_string(),
+            user_id: unescape(fields.get(9)),
+        }),
+        "fpr" => Record::Fingerprint(fields.get(9).to_string()),
+        "grp" => Record::Keygrip(fields.get(9).to_string()),
+        "tru" => Record::Trust(TrustRecord {
+            stale: !fields.get(1).is_empty(),
+            trust_model: fields.number(2)?,
+            created: fields.timestamp(3)?,
+            expires: fields.timestamp(4)?,
+            marginals_needed: fields.number(5)?,
+            completes_needed: fields.number(6)?,
+            max_cert_depth: fields.number(7)?,
+        }),
+        "" => return Err(GpgSshError::Parse(format!("Missing record type in the line: {line}"))),
+        record_type => Record::Other(record_type.to_string()),
+    })
+}
+
+/// Parses every line of the `--with-colons` output
+pub fn parse_records(output: &str) -> Result<Vec<Record>> {
+    output.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_line)
+        .collect()
+}
+
+/// A primary key or subkey, together with its fingerprint and keygrip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    pub record: KeyRecord,
+    pub fingerprint: String,
+    /// Only listed when gpg is called with `--with-keygrip`
+    pub keygrip: Option<String>,
+}
+
+/// A primary key with its user ids and subkeys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyBlock {
+    pub primary: Key,
+    pub uids: Vec<UidRecord>,
+    pub subkeys: Vec<Key>,
+}
+
+impl KeyBlock {
+    fn last_key(&mut self) -> &mut Key {
+        self.subkeys.last_mut().unwrap_or(&mut self.primary)
+    }
+}
+
+/// Builds the key tree out of the records of `gpg --list-keys --with-colons`
+pub fn key_blocks(records: Vec<Record>) -> Result<Vec<KeyBlock>> {
+    let mut blocks: Vec<KeyBlock> = Vec::new();
+
+    for record in records {
+        match record {
+            Record::Key(key) if !key.kind.is_subkey() => blocks.push(KeyBlock {
+                primary: Key { record: key, fingerprint: String::new(), keygrip: None },
+                uids: Vec::new(),
+                subkeys: Vec::new(),
+            }),
+            Record::Trust(_) | Record::Other(_) => {},
+            record => {
+                let block = blocks.last_mut().ok_or_else(|| GpgSshError::Parse(
+                    format!("Found a record before the first primary key: {record:?}")
+                ))?;
+
+                match record {
+                    Record::Key(key) => block.subkeys.push(
+                        Key { record: key, fingerprint: String::new(), keygrip: None }
+                    ),
+                    Record::Uid(uid) => block.uids.push(uid),
+                    Record::Fingerprint(fingerprint) => block.last_key().fingerprint = fingerprint,
+                    Record::Keygrip(keygrip) => block.last_key().keygrip = Some(keygrip),
+                    _ => unreachable!(),
+                }
+            },
+        }
+    }
+
+    for block in &blocks {
+        let keys = std::iter::once(&block.primary).chain(block.subkeys.iter());
+        if let Some(key) = keys.into_iter().find(|key| key.fingerprint.is_empty()) {
+            return Err(GpgSshError::Parse(format!("The key {} has no fingerprint", key.record.key_id)))
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Parses the output of `gpg --list-keys --with-colons` into the key tree
+pub fn parse_key_blocks(output: &str) -> Result<Vec<KeyBlock>> {
+    key_blocks(parse_records(output)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST_KEYS: &str = include_str!("testdata/list_keys.txt");
+
+    #[test]
+    fn parse_pub_line() {
+        let record = parse_line("pub:u:255:22:510DF3503748F565:1792314498:1823850498::u:::cESCA:::+::ed25519:::0:").unwrap();
+
+        assert_eq!(record, Record::Key(KeyRecord {
+            kind: KeyKind::Public,
+            validity: Validity::Ultimate,
+            key_length: 255,
+            algorithm: 22,
+            key_id: "510DF3503748F565".to_string(),
+            created: Some(1792314498),
+            expires: Some(1823850498),
+            ownertrust: Validity::Ultimate,
+            capabilities: Capabilities { encrypt: false, sign: false, certify: true, authenticate: false },
+            overall_capabilities: Capabilities { encrypt: true, sign: true, certify: true, authenticate: true },
+            disabled: false,
+            serial_number: Some("+".to_string()),
+            curve: Some("ed25519".to_string()),
+        }));
+    }
+
+    #[test]
+    fn parse_short_lines() {
+        assert_eq!(parse_line("fpr:::::::::ABCD").unwrap(), Record::Fingerprint("ABCD".to_string()));
+        assert!(matches!(parse_line("sub:e:3072:1:0C2E24E97AD47E32").unwrap(), Record::Key(KeyRecord {
+            validity: Validity::Expired, key_length: 3072, algorithm: 1, created: None, curve: None, ..
+        })));
+        assert_eq!(parse_line("sig:::1").unwrap(), Record::Other("sig".to_string()));
+    }
+
+    #[test]
+    fn unknown_validity_does_not_fail() {
+        assert!(matches!(parse_line("uid:x::::").unwrap(), Record::Uid(UidRecord { validity: Validity::Unknown, .. })));
+
+        let listing = LIST_KEYS.replacen("pub:u:", "pub:z:", 1);
+        let blocks = parse_key_blocks(&listing).unwrap();
+        assert_eq!(blocks[0].primary.record.validity, Validity::Unknown);
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn algorithm_names_and_capabilities() {
+        let key = |line: &str| match parse_line(line).unwrap() {
+            Record::Key(key) => key,
+            record => panic!("not a key record: {record:?}"),
+        };
+
+        let ed25519 = key("sub:u:255:22:0C2E24E97AD47E32:1792314498::::::sa:::::ed25519::");
+        assert_eq!(ed25519.algorithm_name(), "ed25519");
+        assert_eq!(ed25519.capabilities.to_string(), "sa");
+        assert_eq!(key("sub:u:3072:1:0C2E24E97AD47E32:1792314498::::::e:::::::").algorithm_name(), "rsa3072");
+        assert_eq!(key("pub:u:256:19:0C2E24E97AD47E32:1792314498::::::cESCA:::::nistp256::").algorithm_name(), "nistp256");
+    }
+
+    #[test]
+    fn parse_tru_line() {
+        assert_eq!(parse_line("tru:o:1:1792314498:0:3:1:5").unwrap(), Record::Trust(TrustRecord {
+            stale: true,
+            trust_model: 1,
+            created: Some(1792314498),
+            expires: None,
+            marginals_needed: 3,
+            completes_needed: 1,
+            max_cert_depth: 5,
+        }));
+    }
+
+    #[test]
+    fn parse_invalid_lines() {
+        assert!(parse_line("pub:u:abc:22:510DF3503748F565").is_err());
+        assert!(parse_line(":::").is_err());
+    }
+
+    #[test]
+    fn parse_uid_with_escapes() {
+        let record = parse_line("uid:u::::1792314667::7B1319190C8CB8E36F8D2B77C7A8FDDDE72CAC5F::Zoë Colon\\x3a Test (a\\x5cb\\x3a c) <zoe@example.com>::::::::::0:").unwrap();
+
+        let Record::Uid(uid) = record else { panic!("Expected a uid record, got {record:?}") };
+        assert_eq!(uid.user_id, "Zoë Colon: Test (a\\b: c) <zoe@example.com>");
+    }
+
+    #[test]
+    fn unescape_c_escapes() {
+        assert_eq!(unescape("a\\x3ab"), "a:b");
+        assert_eq!(unescape("line\\nbreak\\ttab"), "line\nbreak\ttab");
+        assert_eq!(unescape("\\xc3\\xa6\\xc3\\xb8\\xc3\\xa5"), "æøå");
+        assert_eq!(unescape("trailing\\"), "trailing\\");
+        assert_eq!(unescape("bad \\xZZ and \\q"), "bad \\xZZ and \\q");
+        assert_eq!(unescape("\\xff"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        for name in [
+            "Alice (SSH Key) <alice@example.com>",
+            "Zoë Ünïcødé (日本語 🔑) <zoe@example.com>",
+            "Colon: Name (comment: with colons) <a:b@example.com>",
+            "Back\\slash (C:\\Users\\alice) <alice@example.com>",
+            "Escape-like \\x3a (not \\n an escape) <x@example.com>",
+            "Control\tchars\nand\x7fdel",
+        ] {
+            let escaped = escape(name);
+            assert!(!escaped.contains(':'), "{escaped}");
+            assert_eq!(unescape(&escaped), name);
+        }
+    }
+
+    #[test]
+    fn format_dates() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951782400), "2000-02-29");
+        assert_eq!(format_date(1726876800), "2024-09-21");
+        assert_eq!(format_date(1792314498), "2026-10-18");
+    }
+
+    #[test]
+    fn parse_key_tree() {
+        let blocks = parse_key_blocks(LIST_KEYS).unwrap();
+
+        assert_eq!(blocks.len(), 2);
+        let alice = &blocks[0];
+        assert_eq!(alice.primary.fingerprint, "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(alice.primary.keygrip.as_deref(), Some("53F3A88000CD6781B42018E9C2AD36EC933509B2"));
+        assert_eq!(alice.uids.len(), 1);
+        assert_eq!(alice.uids[0].user_id, "Alice (SSH Key) <alice@example.com>");
+        assert_eq!(alice.subkeys.len(), 3);
+        assert!(alice.subkeys[2].record.capabilities.authenticate);
+        assert_eq!(alice.subkeys[2].fingerprint, "5BCC596B65ED955F64C31BBC0C2E24E97AD47E32");
+        assert_eq!(alice.subkeys[2].keygrip.as_deref(), Some("0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243"));
+
+        let bob = &blocks[1];
+        assert_eq!(bob.uids[0].user_id, "Bob (SSH Key) <bob@example.com>");
+        assert!(bob.subkeys.is_empty());
+    }
+
+    #[test]
+    fn key_tree_needs_primary_key_and_fingerprints() {
+        assert!(parse_key_blocks("sub:u:255:22:143934649D916D66:1792314498::::::s:::::ed25519::").is_err());
+        assert!(parse_key_blocks("pub:u:255:22:510DF3503748F565:1792314498:::u:::cESCA:::::ed25519:::0:").is_err());
+    }
+}
@@ -1,41 +1,105 @@
+use std::collections::HashMap;
 use std::process::{Command, Stdio};
 use log::{debug, warn};
 use tempfile::NamedTempFile;
 use std::io::Write;
-use super::helper_fn::cmd_error_handler;
+use crate::error::Result;
+use crate::ssh::SshPublicKey;
+use super::backend::GpgBackend;
+use super::info::{AuthSubkey, SshKeyInfo};
+use super::helper_fn::run_command;
+use super::packet::parse_public_keys;
 
 
-pub(crate) fn get_public_ssh_keys(
+/// The fingerprint of a key id naming exactly one (sub)key, e.g. from [`AuthSubkey::export_key_id`]
+fn exact_fingerprint(key_id: &str) -> Option<String> {
+    key_id.strip_suffix('!')
+        .filter(|fingerprint| fingerprint.len() == 40 && fingerprint.chars().all(|char| char.is_ascii_hexdigit()))
+        .map(str::to_uppercase)
+}
+
+/// Reads the SSH public keys of the exact subkeys from a single `gpg --export`, by fingerprint
+///
+/// Fails if gpg does, but an export the packet parser does not understand is left to the fallback.
+fn export_exact_ssh_keys(backend: &dyn GpgBackend, public_key_ids: &[String]) -> Result<HashMap<String, SshPublicKey>> {
+    let exact_key_ids: Vec<String> = public_key_ids.iter()
+        .filter(|key_id| exact_fingerprint(key_id).is_some())
+        .cloned()
+        .collect();
+    if exact_key_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let export = backend.export_keys(&exact_key_ids)?;
+    Ok(match parse_public_keys(&export) {
+        Ok(public_keys) => public_keys.iter()
+            .filter_map(|public_key| Some((public_key.fingerprint.clone(), public_key.ssh_public_key()?)))
+            .collect(),
+        Err(err) => {
+            debug!("Could not read the exported keys, exporting them one by one: {err}");
+            HashMap::new()
+        },
+    })
+}
+
+/// Exports the public SSH key of each of the given GPG keys
+///
+/// The keys are returned in the OpenSSH format, e.g. `ssh-ed25519 AAAA... openpgp:0xAAB1D083`.
+/// Use [`AuthSubkey::export_key_id`](super::AuthSubkey::export_key_id) to export a specific auth subkey.
+/// Those are all read from a single `gpg --export`, the other key ids (and algorithms SSH does not know)
+/// are exported one by one with `gpg --export-ssh-key`, which also picks the auth subkey.
+pub fn get_public_ssh_keys(
+    backend: &dyn GpgBackend,
     public_key_ids: Vec<String>,
-) -> Vec<String> {
-    let ssh_public_key: Vec<String> = public_key_ids.iter().map(|public_key| {
-        let mut gpg_command = Command::new("gpg");
-        gpg_command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped())
-            .arg("--export-ssh-key")
-            .arg(public_key);
-        let gpg_output = gpg_command
-            .output()
-            .expect("Failed to execute command");
-
-        cmd_error_handler(&gpg_command, &gpg_output);
-
-        debug!("SSH Public Key: {}", String::from_utf8_lossy(gpg_output.stdout.as_slice()));
-        String::from_utf8(gpg_output.stdout).unwrap().trim().to_string()
-    }).collect();
-
-    ssh_public_key
+) -> Result<Vec<String>> {
+    let exported = export_exact_ssh_keys(backend, &public_key_ids)?;
+
+    public_key_ids.iter().map(|public_key| {
+        let ssh_public_key = match exact_fingerprint(public_key).and_then(|fingerprint| exported.get(&fingerprint)) {
+            Some(ssh_public_key) => ssh_public_key.to_string(),
+            None => backend.export_ssh_key(public_key)?.trim().to_string(),
+        };
+
+        debug!("SSH Public Key: {}", ssh_public_key);
+        Ok(ssh_public_key)
+    }).collect()
 }
 
 
-pub(crate) fn ssh_copy_id(
+/// The SSH public keys of all the auth subkeys, by keygrip of the auth subkey
+///
+/// They are read from a single `gpg --export`, only the auth subkeys missing from it are exported
+/// one by one with `gpg --export-ssh-key`. Every auth subkey has an entry, with the error for the ones
+/// gpg cannot export for SSH (e.g. brainpool curves), so the caller decides whether to skip them.
+pub fn ssh_public_keys(backend: &dyn GpgBackend, ssh_keys: &[SshKeyInfo]) -> Result<HashMap<String, Result<SshPublicKey>>> {
+    let auth_subkeys: Vec<&AuthSubkey> = ssh_keys.iter().flat_map(|ssh_key| &ssh_key.auth_subkeys).collect();
+    let export_key_ids: Vec<String> = auth_subkeys.iter().map(|auth_subkey| auth_subkey.export_key_id()).collect();
+    let mut exported = export_exact_ssh_keys(backend, &export_key_ids)?;
+
+    Ok(auth_subkeys.iter().map(|auth_subkey| {
+        let ssh_public_key = match exported.remove(&auth_subkey.fingerprint) {
+            Some(ssh_public_key) => Ok(ssh_public_key),
+            None => backend.export_ssh_key(&auth_subkey.export_key_id())
+                .and_then(|ssh_public_key| SshPublicKey::parse(ssh_public_key.trim())),
+        };
+        (auth_subkey.keygrip.clone(), ssh_public_key)
+    }).collect())
+}
+
+
+/// Uploads the public SSH keys of the given GPG keys to a server using `ssh-copy-id`
+///
+/// The `args` are passed on to `ssh-copy-id`, except `-i` which is replaced by the exported keys.
+pub fn ssh_copy_id(
+    backend: &dyn GpgBackend,
     args: Vec<String>,
     public_key_ids: Vec<String>,
     keep_temp_files: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ssh_public_key = get_public_ssh_keys(public_key_ids);
+) -> Result<()> {
+    let ssh_public_key = get_public_ssh_keys(backend, public_key_ids)?;
 
     let mut file = NamedTempFile::with_suffix(".pub")?;
-    write!(file, "{}\n", ssh_public_key.as_slice().join("\n"))?;
+    writeln!(file, "{}", ssh_public_key.as_slice().join("\n"))?;
     file.flush()?;
 
     let mut command = Command::new("ssh-copy-id");
@@ -53,19 +117,103 @@ pub(crate) fn ssh_copy_id(
         }
     }
 
-    if detect_argument_i.len() > 0 {
+    if !detect_argument_i.is_empty() {
         warn!("SSH Public key is already provided, so the following arguments are skipped: {:?}", detect_argument_i)
     }
     
-    let output = command
-        .output()
-        .expect("Failed to execute command");
+    let result = run_command(&mut command);
 
     if ! keep_temp_files {
         file.close()?;
     }
 
-    cmd_error_handler(&command, &output);
-
+    result?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GpgSshError;
+    use crate::gpg::{gpg_keys, MockBackend};
+
+    #[test]
+    fn get_public_ssh_keys_trims_output() {
+        let backend = MockBackend::new("").with_ssh_key(
+            "0E42E055657C72FFB213CF83510DF3503748F565",
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFYmEqP9At0MNxGqeaoiLouSvzmTbE1MRiWLgRQI3RCe openpgp:0x7AD47E32\n",
+        );
+
+        assert_eq!(
+            get_public_ssh_keys(&backend, vec!["0E42E055657C72FFB213CF83510DF3503748F565".to_string()]).unwrap(),
+            vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFYmEqP9At0MNxGqeaoiLouSvzmTbE1MRiWLgRQI3RCe openpgp:0x7AD47E32"],
+        );
+    }
+
+    #[test]
+    fn get_public_ssh_keys_reads_subkeys_from_one_export() {
+        let backend = MockBackend::new("")
+            .with_export(include_bytes!("testdata/export.gpg"))
+            .with_ssh_key("EB1966FF8BF2B4A138B547AFCC5603843595DECA!", "unsupported by the packet parser")
+            .with_ssh_key("B208D48E415FF8EAAD4E595B8FEAA54E9F9E62F9", "ssh-ed25519 AAAA openpgp:0x5835E8BE\n");
+        let key_ids = vec![
+            "15ED47CE82B6D431421218773762ED355835E8BE!".to_string(),
+            "54681d4b9e33325f0825b306605eb74bcc4999ee!".to_string(),
+            "EB1966FF8BF2B4A138B547AFCC5603843595DECA!".to_string(),
+            "B208D48E415FF8EAAD4E595B8FEAA54E9F9E62F9".to_string(),
+        ];
+
+        let ssh_public_keys = get_public_ssh_keys(&backend, key_ids.clone()).unwrap();
+
+        let expected: Vec<&str> = include_str!("testdata/export_ssh_keys.txt").lines().collect();
+        assert_eq!(ssh_public_keys, vec![
+            expected[0], expected[1], "unsupported by the packet parser", "ssh-ed25519 AAAA openpgp:0x5835E8BE",
+        ]);
+        assert_eq!(backend.calls(), vec![
+            format!("--export {} {} {}", key_ids[0], key_ids[1], key_ids[2]),
+            format!("--export-ssh-key {}", key_ids[2]),
+            format!("--export-ssh-key {}", key_ids[3]),
+        ]);
+    }
+
+    #[test]
+    fn ssh_public_keys_from_one_export_by_keygrip() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys_export.txt"))
+            .with_export(include_bytes!("testdata/export.gpg"));
+        let ssh_keys = gpg_keys(&backend, None).unwrap();
+        let calls = backend.calls().len();
+
+        let ssh_public_keys = ssh_public_keys(&backend, &ssh_keys).unwrap();
+
+        let expected: Vec<&str> = include_str!("testdata/export_ssh_keys.txt").lines().collect();
+        assert_eq!(ssh_public_keys.len(), 5);
+        assert_eq!(ssh_public_keys["EBD7F569BCBD6B7791C8AB9AACD11A568024D544"].as_ref().unwrap().to_string(), expected[0]);
+        assert_eq!(ssh_public_keys["42A8465CE64CACC3FA58E2729A453D05B076941F"].as_ref().unwrap().to_string(), expected[4]);
+        assert_eq!(backend.calls().len(), calls + 1);
+        assert!(backend.calls()[calls].starts_with("--export 15ED47CE82B6D431421218773762ED355835E8BE! "));
+    }
+
+    #[test]
+    fn ssh_public_keys_keep_failed_exports() {
+        // Without the binary export every key is exported on its own, only one of them works
+        let ed25519 = include_str!("testdata/export_ssh_keys.txt").lines().next().unwrap();
+        let backend = MockBackend::new(include_str!("testdata/list_keys_export.txt"))
+            .with_ssh_key("15ED47CE82B6D431421218773762ED355835E8BE!", ed25519);
+        let ssh_keys = gpg_keys(&backend, None).unwrap();
+
+        let ssh_public_keys = ssh_public_keys(&backend, &ssh_keys).unwrap();
+        assert_eq!(ssh_public_keys.len(), 5);
+        assert_eq!(ssh_public_keys["EBD7F569BCBD6B7791C8AB9AACD11A568024D544"].as_ref().unwrap().to_string(), ed25519);
+        assert!(matches!(
+            ssh_public_keys["612E052E8543B93089C84E9E58837E4E240EBB29"],
+            Err(GpgSshError::CommandFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn get_public_ssh_keys_fails_on_unknown_key() {
+        let backend = MockBackend::new("");
+
+        assert!(get_public_ssh_keys(&backend, vec!["DEADBEEF".to_string()]).is_err());
+    }
+}
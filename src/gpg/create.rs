@@ -1,107 +1,585 @@
+use std::collections::HashSet;
 use std::fmt::{Display};
-use std::process::{Command, Stdio};
-use log::trace;
-use super::gpg_keys;
-use super::helper_fn::{cmd_error_handler, get_fingerprint_of_key};
+use std::str::FromStr;
+use log::{error, trace, warn};
+use super::{gpg_keys, AuthSubkey, SshKeyInfo};
+use super::copy_id::ssh_public_keys;
+use super::expire::Expire;
+use super::passphrase::Passphrase;
+use crate::error::{GpgSshError, Result};
+use super::backend::GpgBackend;
+use super::colons::{parse_key_blocks, parse_line, Record};
 use super::set::mark_gpg_key_as_ssh_key;
 
-#[derive(Debug)]
-pub(crate) enum KeyAlgo {
+/// The algorithm used for the primary key and the subkeys created by [`create`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyAlgo {
+    #[default]
     ED25519,
+    RSA3072,
+    RSA4096,
+    NISTP256,
+    NISTP384,
+    NISTP521,
+}
+
+impl KeyAlgo {
+    /// Every algorithm, in the order they are offered to the user
+    pub const ALL: [KeyAlgo; 6] = [
+        KeyAlgo::ED25519, KeyAlgo::RSA3072, KeyAlgo::RSA4096, KeyAlgo::NISTP256, KeyAlgo::NISTP384, KeyAlgo::NISTP521,
+    ];
+
+    /// The name gpg uses for the algorithm, e.g. `ed25519`
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyAlgo::ED25519 => "ed25519",
+            KeyAlgo::RSA3072 => "rsa3072",
+            KeyAlgo::RSA4096 => "rsa4096",
+            KeyAlgo::NISTP256 => "nistp256",
+            KeyAlgo::NISTP384 => "nistp384",
+            KeyAlgo::NISTP521 => "nistp521",
+        }
+    }
+
+    /// The algorithm of the sign and auth subkeys, gpg would make a NIST curve subkey an ECDH (encryption) key
+    /// unless ECDSA is asked for
+    pub fn signing_algo(&self) -> &'static str {
+        match self {
+            KeyAlgo::NISTP256 => "nistp256/ecdsa",
+            KeyAlgo::NISTP384 => "nistp384/ecdsa",
+            KeyAlgo::NISTP521 => "nistp521/ecdsa",
+            key_algo => key_algo.name(),
+        }
+    }
+
+    /// The algorithm of the encryption subkey, as the signing algorithms of some curves cannot encrypt
+    pub fn encryption_algo(&self) -> &'static str {
+        match self {
+            KeyAlgo::ED25519 => "cv25519",
+            KeyAlgo::RSA3072 => "rsa3072",
+            KeyAlgo::RSA4096 => "rsa4096",
+            KeyAlgo::NISTP256 => "nistp256",
+            KeyAlgo::NISTP384 => "nistp384",
+            KeyAlgo::NISTP521 => "nistp521",
+        }
+    }
+
+    /// The type of the SSH key OpenSSH expects for an auth subkey of this algorithm
+    pub fn ssh_key_type(&self) -> &'static str {
+        match self {
+            KeyAlgo::ED25519 => "ssh-ed25519",
+            KeyAlgo::RSA3072 | KeyAlgo::RSA4096 => "ssh-rsa",
+            KeyAlgo::NISTP256 => "ecdsa-sha2-nistp256",
+            KeyAlgo::NISTP384 => "ecdsa-sha2-nistp384",
+            KeyAlgo::NISTP521 => "ecdsa-sha2-nistp521",
+        }
+    }
 }
 
 impl Display for KeyAlgo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", match self {
-            KeyAlgo::ED25519 => "ed25519",
-        }.to_string())
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for KeyAlgo {
+    type Err = GpgSshError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        KeyAlgo::ALL.into_iter()
+            .find(|key_algo| key_algo.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| GpgSshError::Parse(format!("Unknown key algorithm: {name}")))
     }
 }
 
 
-fn add_sub_key(key_id: &str, key_algo: &str, key_type: &str) {
-    let mut command = Command::new("gpg");
-    command.stdin(Stdio::null())
-        .arg("--quick-add-key")
-        .arg(key_id)
-        .arg(key_algo)
-        .arg(key_type)
-        .arg("never");
-    let output = command
-        .output()
-        .expect("Failed to execute command");
+fn add_sub_key(
+    backend: &dyn GpgBackend,
+    key_id: &str,
+    key_algo: &str,
+    key_type: &str,
+    expire: &Expire,
+    passphrase: &Passphrase,
+) -> Result<()> {
+    backend.quick_add_key(key_id, key_algo, key_type, &expire.to_string(), passphrase)
+}
 
-    cmd_error_handler(&command, &output);
+fn add_sub_key_sign(backend: &dyn GpgBackend, key_id: &str, key_algo: &KeyAlgo, expire: &Expire, passphrase: &Passphrase) -> Result<()> {
+    add_sub_key(backend, key_id, key_algo.signing_algo(), "sign", expire, passphrase)
 }
 
-fn add_sub_key_sign(key_id: &str, key_algo: &KeyAlgo) {
-    add_sub_key(key_id, key_algo.to_string().as_str(), "sign")
+fn add_sub_key_encr(backend: &dyn GpgBackend, key_id: &str, key_algo: &KeyAlgo, expire: &Expire, passphrase: &Passphrase) -> Result<()> {
+    add_sub_key(backend, key_id, key_algo.encryption_algo(), "encr", expire, passphrase)
 }
 
-fn add_sub_key_encr(key_id: &str, key_algo: &KeyAlgo) {
-    add_sub_key(
-        key_id,
-        match key_algo {
-            KeyAlgo::ED25519 => "cv25519",
-        },
-        "encr"
-    )
+fn add_sub_key_auth(backend: &dyn GpgBackend, key_id: &str, key_algo: &KeyAlgo, expire: &Expire, passphrase: &Passphrase) -> Result<()> {
+    add_sub_key(backend, key_id, key_algo.signing_algo(), "auth", expire, passphrase)
+}
+
+/// Which subkeys [`create`] adds to the primary key, by default all of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubkeySelection {
+    pub sign: bool,
+    pub encr: bool,
+    /// Only an auth subkey is marked to be used for SSH
+    pub auth: bool,
+}
+
+impl Default for SubkeySelection {
+    fn default() -> Self {
+        SubkeySelection { sign: true, encr: true, auth: true }
+    }
 }
 
-fn add_sub_key_auth(key_id: &str, key_algo: &KeyAlgo) {
-    add_sub_key(key_id, key_algo.to_string().as_str(), "auth")
+/// How [`create`] generates the key, the defaults are an ed25519 key with all the subkeys, which never expires
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// The comment of the user id, `SSH Key` if `None`
+    pub comment: Option<String>,
+    pub key_algo: KeyAlgo,
+    /// When the primary key expires
+    pub expire: Expire,
+    /// When the sign subkey expires
+    pub sign_expire: Expire,
+    /// When the encryption subkey expires
+    pub encr_expire: Expire,
+    /// When the auth subkey expires
+    pub auth_expire: Expire,
+    pub subkeys: SubkeySelection,
+    /// Keep the key if a step after generating the primary key fails, instead of deleting it
+    pub keep_partial: bool,
+    /// How gpg gets the passphrase of the new key, by default pinentry asks
+    pub passphrase: Passphrase,
 }
 
+/// Refuses user ids gpg would misread, as `(` and `)` delimit the comment and `<` and `>` the email
+fn validate_user_id(name: &str, email: &str, comment: &str) -> Result<()> {
+    let invalid = |msg: String| Err(GpgSshError::InvalidArgument(msg));
 
-pub(crate) fn create(
+    if name.trim().is_empty() {
+        return invalid("The name must not be empty".to_string());
+    }
+    if let Some(char) = name.chars().find(|char| "()<>".contains(*char)) {
+        return invalid(format!("The name `{name}` must not contain `{char}`"));
+    }
+    if let Some(char) = comment.chars().find(|char| "()<>".contains(*char)) {
+        return invalid(format!("The comment `{comment}` must not contain `{char}`"));
+    }
+    if !email.contains('@') || email.contains(|char: char| char.is_whitespace() || "<>".contains(char)) {
+        return invalid(format!("`{email}` is not an email address"));
+    }
+    Ok(())
+}
+
+/// Fails unless gpg exports the auth subkey as the SSH key type OpenSSH expects for the algorithm
+fn ensure_ssh_key_type(
+    backend: &dyn GpgBackend,
+    ssh_key: &SshKeyInfo,
+    auth_subkey: &AuthSubkey,
+    key_algo: &KeyAlgo,
+) -> Result<()> {
+    let key_type = ssh_public_keys(backend, std::slice::from_ref(ssh_key))?.remove(&auth_subkey.keygrip)
+        .unwrap_or_else(|| Err(GpgSshError::Parse(format!(
+            "gpg could not export the auth subkey of {} as an SSH key", ssh_key.describe(auth_subkey)
+        ))))?
+        .key_type;
+    if key_type != key_algo.ssh_key_type() {
+        return Err(GpgSshError::Parse(format!(
+            "gpg exported the auth subkey of {} as `{key_type}` instead of `{}`, which OpenSSH would expect for {key_algo}",
+            ssh_key.describe(auth_subkey),
+            key_algo.ssh_key_type(),
+        )))
+    }
+    Ok(())
+}
+
+/// Generates a new GPG key with a sign, encrypt and auth subkey (see [`SubkeySelection`])
+/// and marks the auth subkey to be used for SSH
+///
+/// The user id becomes `<name> (<comment>) <<email>>`, see [`CreateOptions`] for the rest.
+/// The user id is checked before gpg is called. Returns the fingerprint of the new key.
+///
+/// If adding a subkey or marking the auth subkey fails, the new key is deleted again
+/// (unless [`CreateOptions::keep_partial`] is set) and the error is returned.
+pub fn create(
+    backend: &dyn GpgBackend,
     name: String,
     email: String,
-    comment: Option<String>,
-    key_algo: Option<KeyAlgo>,
-) {
-    let key_algo = key_algo.unwrap_or(KeyAlgo::ED25519);
-
-    let mut command = Command::new("gpg");
-    command.stdin(Stdio::null()).stderr(Stdio::piped()).stdout(Stdio::piped())
-        .arg("--with-colons")
-        .arg("--quick-generate-key")
-        .arg(format!("{name} ({comment}) <{email}>",
-             comment=comment.unwrap_or("SSH Key".to_string())))
-        .arg(key_algo.to_string())
-        .arg("cert")
-        .arg("never");
-    let output = command
-        .output()
-        .expect("Failed to execute command");
-
-    cmd_error_handler(&command, &output);
-
-
-    let stdout = String::from_utf8(output.stdout).unwrap();
-    let stdout_as_lines = stdout.trim().lines().rev();
-
-    let mut fpr_key_id = None;
-    // let mut grp_keygrip = None;
-    // let mut uid_name = None;
-    for row in stdout_as_lines {
-        trace!("line: {row}");
-        if row.len() == 0 { break }
-
-        if let Some(id) = get_fingerprint_of_key(row) { fpr_key_id = Some(id) }
-        // if let Some(keygrip) = get_keygrip(row) { grp_keygrip = Some(keygrip) }
-        // if let Some(name) = get_name(row) { uid_name = Some(name) }
-    }
-
-    if let Some(key_id) = fpr_key_id {
-        add_sub_key_sign(key_id, &key_algo);
-        add_sub_key_encr(key_id, &key_algo);
-        add_sub_key_auth(key_id, &key_algo);
-
-        let key_ids = vec![key_id.to_string()];
-        let key_ssh_info= gpg_keys(Some(key_ids));
-
-        for key_ssh in key_ssh_info {
-            mark_gpg_key_as_ssh_key(&vec![key_ssh.auth_keygrip])
+    options: &CreateOptions,
+) -> Result<String> {
+    let key_algo = options.key_algo;
+    let comment = options.comment.as_deref().unwrap_or("SSH Key");
+    validate_user_id(&name, &email, comment)?;
+
+    let stdout = backend.quick_generate_key(
+        &format!("{name} ({comment}) <{email}>"),
+        key_algo.name(),
+        "cert",
+        &options.expire.to_string(),
+        &options.passphrase,
+    )?;
+    trace!("quick-generate-key output: {stdout}");
+
+    // Only the fingerprint is needed to delete the key again, so it is picked out before the listing is parsed
+    let key_id = stdout.lines().find_map(|line| match parse_line(line) {
+        Ok(Record::Fingerprint(fingerprint)) => Some(fingerprint),
+        _ => None,
+    }).ok_or_else(|| GpgSshError::Parse(
+        "Did not find the fingerprint of the created key in the output of gpg".to_string()
+    ))?;
+
+    let mut created = vec!["primary key"];
+    let result = parse_key_blocks(&stdout)
+        .and_then(|_| add_subkeys_and_mark(backend, &key_id, options, &mut created));
+    if let Err(err) = result {
+        if options.keep_partial {
+            warn!("Kept the partially created key {key_id} ({}) for debugging", created.join(", "));
+        } else {
+            roll_back(backend, &key_id, &created);
         }
+        return Err(err);
+    }
+
+    Ok(key_id)
+}
+
+/// The steps of [`create`] after the primary key was generated, `created` lists what was added so far
+fn add_subkeys_and_mark(
+    backend: &dyn GpgBackend,
+    key_id: &str,
+    options: &CreateOptions,
+    created: &mut Vec<&'static str>,
+) -> Result<()> {
+    let key_algo = options.key_algo;
+
+    if options.subkeys.sign {
+        add_sub_key_sign(backend, key_id, &key_algo, &options.sign_expire, &options.passphrase)?;
+        created.push("sign subkey");
+    }
+    if options.subkeys.encr {
+        add_sub_key_encr(backend, key_id, &key_algo, &options.encr_expire, &options.passphrase)?;
+        created.push("encryption subkey");
+    }
+    if !options.subkeys.auth {
+        return Ok(());
+    }
+    add_sub_key_auth(backend, key_id, &key_algo, &options.auth_expire, &options.passphrase)?;
+    created.push("auth subkey");
+
+    let key_ids = vec![key_id.to_string()];
+    let key_ssh_info= gpg_keys(backend, Some(key_ids))?;
+
+    for key_ssh in key_ssh_info {
+        for auth_subkey in &key_ssh.auth_subkeys {
+            ensure_ssh_key_type(backend, &key_ssh, auth_subkey, &key_algo)?;
+        }
+
+        let list_of_keygrip: Vec<&str> = key_ssh.auth_subkeys.iter()
+            .map(|subkey| subkey.keygrip.as_str())
+            .collect();
+        mark_gpg_key_as_ssh_key(backend, &list_of_keygrip)?
+    }
+
+    Ok(())
+}
+
+/// Deletes the partially created key, so no half-built key is left in the keyring
+fn roll_back(backend: &dyn GpgBackend, key_id: &str, created: &[&str]) {
+    match backend.delete_key(key_id) {
+        Ok(()) => warn!("Creating the key failed, deleted the partially created key {key_id} ({})", created.join(", ")),
+        Err(err) => error!(
+            "Creating the key failed and the partially created key {key_id} could not be deleted, \
+            delete it with `gpg --delete-secret-and-public-key {key_id}`: {err}"
+        ),
+    }
+}
+
+/// Adds an auth subkey to an existing secret primary key and marks it to be used for SSH
+///
+/// Returns the new auth subkey.
+pub fn add_auth(backend: &dyn GpgBackend, fingerprint: &str, key_algo: KeyAlgo, expire: Expire) -> Result<AuthSubkey> {
+    let key_ids = vec![fingerprint.to_string()];
+    let existing: HashSet<String> = gpg_keys(backend, Some(key_ids.clone()))?.iter()
+        .flat_map(|ssh_key| ssh_key.auth_subkeys.iter().map(|auth_subkey| auth_subkey.fingerprint.clone()))
+        .collect();
+
+    add_sub_key_auth(backend, fingerprint, &key_algo, &expire, &Passphrase::Pinentry)?;
+
+    let ssh_keys = gpg_keys(backend, Some(key_ids))?;
+    let (ssh_key, auth_subkey) = ssh_keys.iter()
+        .flat_map(|ssh_key| ssh_key.auth_subkeys.iter().map(move |auth_subkey| (ssh_key, auth_subkey)))
+        .find(|(_, auth_subkey)| !existing.contains(&auth_subkey.fingerprint))
+        .ok_or_else(|| GpgSshError::Parse(format!("Did not find the new auth subkey of {fingerprint} in the output of gpg")))?;
+
+    ensure_ssh_key_type(backend, ssh_key, auth_subkey, &key_algo)?;
+    mark_gpg_key_as_ssh_key(backend, &[&auth_subkey.keygrip])?;
+
+    Ok(auth_subkey.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpg::MockBackend;
+
+    const ALICE_SSH_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFYmEqP9At0MNxGqeaoiLouSvzmTbE1MRiWLgRQI3RCe openpgp:0x7AD47E32";
+
+    #[test]
+    fn create_adds_subkeys_and_marks_auth_subkey() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_ssh_key("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!", ALICE_SSH_KEY);
+
+        let fpr = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions::default()).unwrap();
+
+        assert_eq!(fpr, "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(backend.calls(), vec![
+            "--quick-generate-key Alice (SSH Key) <alice@example.com> ed25519 cert never".to_string(),
+            format!("--quick-add-key {fpr} ed25519 sign never"),
+            format!("--quick-add-key {fpr} cv25519 encr never"),
+            format!("--quick-add-key {fpr} ed25519 auth never"),
+            format!("--list-keys {fpr}"),
+            "--list-secret-keys".to_string(),
+            // The canned export is empty, so the key is exported on its own
+            "--export 5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!".to_string(),
+            "--export-ssh-key 5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!".to_string(),
+        ]);
+        assert_eq!(backend.sent_agent_commands(), vec![
+            "KEYATTR 0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243 Use-for-ssh: true",
+        ]);
+    }
+
+    #[test]
+    fn create_uses_matching_encryption_algo() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_ssh_key("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!", "ecdsa-sha2-nistp384 AAAA openpgp:0x7AD47E32");
+
+        let fpr = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions {
+            key_algo: KeyAlgo::NISTP384,
+            ..Default::default()
+        }).unwrap();
+
+        let calls = backend.calls();
+        assert_eq!(calls[0], "--quick-generate-key Alice (SSH Key) <alice@example.com> nistp384 cert never");
+        assert_eq!(calls[1], format!("--quick-add-key {fpr} nistp384/ecdsa sign never"));
+        assert_eq!(calls[2], format!("--quick-add-key {fpr} nistp384 encr never"));
+        assert_eq!(calls[3], format!("--quick-add-key {fpr} nistp384/ecdsa auth never"));
+    }
+
+    #[test]
+    fn create_refuses_unexpected_ssh_key_type() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_ssh_key("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!", ALICE_SSH_KEY);
+
+        let result = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions {
+            key_algo: KeyAlgo::RSA4096,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(GpgSshError::Parse(_))));
+        assert!(backend.sent_agent_commands().is_empty());
+        assert_eq!(backend.calls().last().unwrap(), "--batch --yes --delete-secret-and-public-key 0E42E055657C72FFB213CF83510DF3503748F565");
+    }
+
+    #[test]
+    fn create_passes_passphrase_on_stdin_only() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_ssh_key("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!", ALICE_SSH_KEY);
+
+        create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions {
+            passphrase: Passphrase::Secret("hunter2".to_string()),
+            ..Default::default()
+        }).unwrap();
+
+        let calls = backend.calls();
+        let batch = "--batch --pinentry-mode loopback --passphrase-fd 0";
+        assert_eq!(calls[0], format!("{batch} --quick-generate-key Alice (SSH Key) <alice@example.com> ed25519 cert never"));
+        assert!(calls[1..4].iter().all(|call| call.starts_with(&format!("{batch} --quick-add-key"))));
+        assert!(!calls.iter().any(|call| call.contains("hunter2")));
+    }
+
+    #[test]
+    fn create_rolls_back_on_failure() {
+        let fpr = "0E42E055657C72FFB213CF83510DF3503748F565";
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_failing_call(&format!("--quick-add-key {fpr} cv25519 encr"));
+
+        let result = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions::default());
+
+        assert!(matches!(result, Err(GpgSshError::CommandFailed { .. })));
+        assert_eq!(backend.calls()[2..], [
+            format!("--quick-add-key {fpr} cv25519 encr never"),
+            format!("--batch --yes --delete-secret-and-public-key {fpr}"),
+        ]);
+    }
+
+    #[test]
+    fn create_rolls_back_if_the_listing_cannot_be_parsed() {
+        let fpr = "0E42E055657C72FFB213CF83510DF3503748F565";
+        let listing = include_str!("testdata/quick_generate_key.txt").replace(":1792314498:::u:::cESCA:", ":soon:::u:::cESCA:");
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt")).with_generate_key_output(&listing);
+
+        let result = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions::default());
+
+        assert!(matches!(result, Err(GpgSshError::Parse(_))));
+        assert_eq!(backend.calls()[1..], [format!("--batch --yes --delete-secret-and-public-key {fpr}")]);
+    }
+
+    #[test]
+    fn create_keeps_partial_key_on_request() {
+        let fpr = "0E42E055657C72FFB213CF83510DF3503748F565";
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_agent_response(
+                "KEYATTR 0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243 Use-for-ssh: true",
+                "ERR 67109139 Unknown IPC command",
+            )
+            .with_ssh_key("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!", ALICE_SSH_KEY);
+
+        let result = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions {
+            keep_partial: true,
+            ..Default::default()
+        });
+
+        assert!(matches!(result, Err(GpgSshError::Agent { .. })));
+        assert!(!backend.calls().iter().any(|call| call.contains("--delete-secret-and-public-key")));
+        assert!(backend.calls().contains(&format!("--quick-add-key {fpr} ed25519 auth never")));
+    }
+
+    #[test]
+    fn create_returns_original_error_if_rollback_fails() {
+        let fpr = "0E42E055657C72FFB213CF83510DF3503748F565";
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_failing_call(&format!("--quick-add-key {fpr} ed25519 sign"))
+            .with_failing_call("--batch --yes --delete-secret-and-public-key");
+
+        let result = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions::default());
+
+        match result {
+            Err(GpgSshError::CommandFailed { args, .. }) => assert_eq!(args[0], "--quick-add-key"),
+            result => panic!("Expected the failed --quick-add-key, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn create_passes_comment_and_expiry_on() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_ssh_key("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!", ALICE_SSH_KEY);
+
+        let fpr = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions {
+            comment: Some("Work".to_string()),
+            expire: Expire::Years(2),
+            sign_expire: Expire::Years(1),
+            encr_expire: Expire::Months(6),
+            auth_expire: Expire::Days(90),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(backend.calls()[..4], [
+            "--quick-generate-key Alice (Work) <alice@example.com> ed25519 cert 2y".to_string(),
+            format!("--quick-add-key {fpr} ed25519 sign 1y"),
+            format!("--quick-add-key {fpr} cv25519 encr 6m"),
+            format!("--quick-add-key {fpr} ed25519 auth 90"),
+        ]);
+    }
+
+    #[test]
+    fn create_only_the_selected_subkeys() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"))
+            .with_ssh_key("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!", ALICE_SSH_KEY);
+
+        let fpr = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions {
+            subkeys: SubkeySelection { sign: false, encr: false, auth: true },
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(backend.calls()[..2], [
+            "--quick-generate-key Alice (SSH Key) <alice@example.com> ed25519 cert never".to_string(),
+            format!("--quick-add-key {fpr} ed25519 auth never"),
+        ]);
+        assert_eq!(backend.sent_agent_commands().len(), 1);
+    }
+
+    #[test]
+    fn nothing_is_marked_without_auth_subkey() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"))
+            .with_generate_key_output(include_str!("testdata/quick_generate_key.txt"));
+
+        let fpr = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions {
+            subkeys: SubkeySelection { sign: true, encr: true, auth: false },
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(backend.calls(), vec![
+            "--quick-generate-key Alice (SSH Key) <alice@example.com> ed25519 cert never".to_string(),
+            format!("--quick-add-key {fpr} ed25519 sign never"),
+            format!("--quick-add-key {fpr} cv25519 encr never"),
+        ]);
+        assert!(backend.sent_agent_commands().is_empty());
+    }
+
+    #[test]
+    fn invalid_user_id_is_refused_before_calling_gpg() {
+        let backend = MockBackend::new("");
+        let create = |name: &str, email: &str, comment: &str| create(&backend, name.to_string(), email.to_string(), &CreateOptions {
+            comment: Some(comment.to_string()),
+            ..Default::default()
+        });
+
+        assert!(matches!(create("", "alice@example.com", "SSH"), Err(GpgSshError::InvalidArgument(_))));
+        assert!(matches!(create("Alice <a@b>", "alice@example.com", "SSH"), Err(GpgSshError::InvalidArgument(_))));
+        assert!(matches!(create("Alice", "alice@example.com", "SSH (work)"), Err(GpgSshError::InvalidArgument(_))));
+        assert!(matches!(create("Alice", "alice", "SSH"), Err(GpgSshError::InvalidArgument(_))));
+        assert!(backend.calls().is_empty());
+    }
+
+    #[test]
+    fn add_auth_marks_the_new_subkey() {
+        let after = include_str!("testdata/list_keys_two_auth.txt");
+        let before = after.split("sub:u:255:22:3A543B3BB5E24591").next().unwrap();
+        let backend = MockBackend::new(before)
+            .with_list_keys_after_add_key_output(after)
+            .with_ssh_key("2D7E8C21F36DC2BC308110C43A543B3BB5E24591!", ALICE_SSH_KEY);
+
+        let fpr = "0E42E055657C72FFB213CF83510DF3503748F565";
+        let auth_subkey = add_auth(&backend, fpr, KeyAlgo::ED25519, Expire::Years(1)).unwrap();
+
+        assert_eq!(auth_subkey.fingerprint, "2D7E8C21F36DC2BC308110C43A543B3BB5E24591");
+        assert!(backend.calls().contains(&format!("--quick-add-key {fpr} ed25519 auth 1y")));
+        assert_eq!(backend.sent_agent_commands(), vec![
+            "KEYATTR 74E85BD09EB34D98EE2AC0F717B384B8E28DF93F Use-for-ssh: true",
+        ]);
+    }
+
+    #[test]
+    fn add_auth_fails_without_new_subkey() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys.txt"));
+
+        let result = add_auth(&backend, "0E42E055657C72FFB213CF83510DF3503748F565", KeyAlgo::ED25519, Expire::Never);
+
+        assert!(matches!(result, Err(GpgSshError::Parse(_))));
+        assert!(backend.sent_agent_commands().is_empty());
+    }
+
+    #[test]
+    fn key_algo_names() {
+        assert_eq!("RSA3072".parse::<KeyAlgo>().unwrap(), KeyAlgo::RSA3072);
+        assert_eq!(KeyAlgo::NISTP521.to_string(), "nistp521");
+        assert!("dsa1024".parse::<KeyAlgo>().is_err());
+    }
+
+    #[test]
+    fn create_fails_without_fingerprint() {
+        let backend = MockBackend::new("");
+
+        let result = create(&backend, "Alice".to_string(), "alice@example.com".to_string(), &CreateOptions::default());
+        assert!(matches!(result, Err(GpgSshError::Parse(_))));
+        assert_eq!(backend.calls().len(), 1);
     }
 }
@@ -0,0 +1,137 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::error::{GpgSshError, Result};
+use super::colons::format_date;
+use super::info::now;
+
+/// When a key expires, in one of the forms gpg accepts for `--quick-generate-key` and `--quick-add-key`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Expire {
+    /// `never` (or `0`)
+    #[default]
+    Never,
+    /// `<n>`: in n days
+    Days(u32),
+    /// `<n>w`: in n weeks
+    Weeks(u32),
+    /// `<n>m`: in n months
+    Months(u32),
+    /// `<n>y`: in n years
+    Years(u32),
+    /// `YYYY-MM-DD`: on the date (UTC)
+    Date { year: u16, month: u8, day: u8 },
+}
+
+fn invalid(value: &str, reason: &str) -> GpgSshError {
+    GpgSshError::InvalidArgument(format!(
+        "`{value}` is not a valid expiry ({reason}), use <n>, <n>w, <n>m, <n>y, YYYY-MM-DD or never"
+    ))
+}
+
+// `is_multiple_of` needs a newer Rust than the pinned nixpkgs ships, whose clippy does not know the lint yet
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+fn parse_date(value: &str) -> Option<(u16, u8, u8)> {
+    let mut parts = value.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    Some((year.parse().ok()?, month.parse().ok()?, day.parse().ok()?))
+}
+
+impl FromStr for Expire {
+    type Err = GpgSshError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("never") || value == "0" {
+            return Ok(Expire::Never);
+        }
+
+        if value.contains('-') {
+            let (year, month, day) = parse_date(value).ok_or_else(|| invalid(value, "not a YYYY-MM-DD date"))?;
+            if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+                return Err(invalid(value, "no such day"));
+            }
+            // Both are YYYY-MM-DD, so they compare like dates
+            if *value <= *format_date(now()) {
+                return Err(invalid(value, "the date is not in the future"));
+            }
+            return Ok(Expire::Date { year, month, day });
+        }
+
+        let (number, unit) = match value.char_indices().last() {
+            Some((index, unit)) if unit.is_ascii_alphabetic() => (&value[..index], Some(unit.to_ascii_lowercase())),
+            _ => (value, None),
+        };
+        let number: u32 = number.parse().map_err(|_| invalid(value, "not a number"))?;
+        if number == 0 {
+            return Err(invalid(value, "the key would expire right away"));
+        }
+
+        match unit {
+            None | Some('d') => Ok(Expire::Days(number)),
+            Some('w') => Ok(Expire::Weeks(number)),
+            Some('m') => Ok(Expire::Months(number)),
+            Some('y') => Ok(Expire::Years(number)),
+            Some(_) => Err(invalid(value, "unknown unit")),
+        }
+    }
+}
+
+impl Display for Expire {
+    /// The form passed on to gpg
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expire::Never => write!(f, "never"),
+            Expire::Days(days) => write!(f, "{days}"),
+            Expire::Weeks(weeks) => write!(f, "{weeks}w"),
+            Expire::Months(months) => write!(f, "{months}m"),
+            Expire::Years(years) => write!(f, "{years}y"),
+            Expire::Date { year, month, day } => write!(f, "{year:04}-{month:02}-{day:02}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_gpg_forms() {
+        assert_eq!("never".parse::<Expire>().unwrap(), Expire::Never);
+        assert_eq!("0".parse::<Expire>().unwrap(), Expire::Never);
+        assert_eq!("30".parse::<Expire>().unwrap(), Expire::Days(30));
+        assert_eq!("2w".parse::<Expire>().unwrap(), Expire::Weeks(2));
+        assert_eq!("6m".parse::<Expire>().unwrap(), Expire::Months(6));
+        assert_eq!("1Y".parse::<Expire>().unwrap(), Expire::Years(1));
+        assert_eq!("2999-02-28".parse::<Expire>().unwrap(), Expire::Date { year: 2999, month: 2, day: 28 });
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for value in ["never", "30", "2w", "6m", "1y", "2999-02-28"] {
+            assert_eq!(value.parse::<Expire>().unwrap().to_string(), value);
+        }
+    }
+
+    #[test]
+    fn refuse_invalid_values() {
+        for value in ["", "soon", "1x", "-1", "y", "2999-13-01", "2999-02-29", "2999-2-1", "2000-01-01"] {
+            assert!(
+                matches!(value.parse::<Expire>(), Err(GpgSshError::InvalidArgument(_))),
+                "`{value}` should be refused",
+            );
+        }
+        assert_eq!("2996-02-29".parse::<Expire>().unwrap(), Expire::Date { year: 2996, month: 2, day: 29 });
+    }
+}
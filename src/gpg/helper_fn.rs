@@ -1,74 +1,55 @@
-use std::process::{Command, exit, Output};
-use std::str::from_utf8;
-use log::error;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use crate::error::{GpgSshError, Result};
 
-pub(crate) fn get_column(line: &str, column: usize) -> &str {
-    let mut columns = line.split(":");
-    let result = columns.nth(column);
-    result.unwrap()
-}
-
-pub(super) fn get_type(line: &str) -> &str {
-    get_column(line, 0)
+fn program_name(command: &Command) -> String {
+    command.get_program().to_string_lossy().to_string()
 }
 
+/// Runs the command and turns both a failed spawn and a non-zero exit code into an error
+pub(super) fn run_command(command: &mut Command) -> Result<Output> {
+    let output = command.output().map_err(|source| GpgSshError::Spawn {
+        program: program_name(command),
+        source,
+    })?;
 
-pub(super) fn get_fingerprint_of_key(line: &str) -> Option<&str> {
-    if get_type(line) == "fpr" {
-        Some(get_column(line, 9))
-    } else {
-        None
-    }
+    cmd_error_handler(command, &output)?;
+    Ok(output)
 }
 
-pub(super) fn get_name(line: &str) -> Option<&str> {
-    if get_type(line) == "uid" {
-        Some(get_column(line, 9))
-    } else {
-        None
+/// Like [`run_command`], but writes `input` to stdin of the command, e.g. a passphrase
+/// that must not show up in the arguments
+pub(super) fn run_command_with_input(command: &mut Command, input: &[u8]) -> Result<Output> {
+    let mut child = command.stdin(Stdio::piped()).spawn().map_err(|source| GpgSshError::Spawn {
+        program: program_name(command),
+        source,
+    })?;
+
+    // Dropping stdin closes it, so the command does not wait for more input
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
     }
-}
-
-pub(super) fn get_keygrip(line: &str) -> Option<&str> {
-    if get_type(line) == "grp" {
-        Some(get_column(line, 9))
-    } else {
-        None
-    }
-}
+    let output = child.wait_with_output()?;
 
-pub(super) fn is_sub_auth_key(line: &str) -> bool {
-    get_column(line, 0) == "sub" &&
-    get_column(line, 11) == "a"
+    cmd_error_handler(command, &output)?;
+    Ok(output)
 }
 
-pub(super) fn cmd_error_handler(command: &Command, output: &Output) {
-    if output.status.success() { return; }
+pub(super) fn cmd_error_handler(command: &Command, output: &Output) -> Result<()> {
+    if output.status.success() { return Ok(()); }
 
-    let mut stdout;
-    let mut stderr;
-
-    if output.stdout.len() > 0 {
-        stdout = String::from("Stdout: ");
-        stdout.push_str(from_utf8(&output.stdout).unwrap());
-    } else {
-        stdout = String::from("Stdout: ");
-    }
-
-    if output.stderr.len() > 0 {
-        stderr = String::from("Stderr: ");
-        stderr.push_str(from_utf8(&output.stderr).unwrap());
-    } else {
-        stderr = String::from("No Stderr")
-    }
-
-    error!(
-        "Failed CMD: {:?} - Args: {:?} - {:?} - {:?} - Exit code: {}",
-        command.get_program(),
-        command.get_args(),
-        stdout, stderr,
-        output.status.code().unwrap(),
-    );
+    Err(GpgSshError::CommandFailed {
+        program: program_name(command),
+        args: command.get_args().map(|arg| arg.to_string_lossy().to_string()).collect(),
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        exit_code: output.status.code(),
+    })
+}
 
-    exit(1);
+pub(super) fn stdout_to_string(command: &Command, output: Output) -> Result<String> {
+    String::from_utf8(output.stdout).map_err(|source| GpgSshError::Utf8 {
+        program: program_name(command),
+        source,
+    })
 }
@@ -1,148 +1,524 @@
-use std::collections::HashSet;
-use std::process::{Command, exit, Stdio};
-use log::{error, trace};
-use crate::gpg::helper_fn::{cmd_error_handler, get_fingerprint_of_key, get_keygrip, get_name, get_type, is_sub_auth_key};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{trace, warn};
+use serde::Serialize;
+use crate::error::{GpgSshError, Result};
+use crate::gpg::backend::GpgBackend;
+use crate::gpg::keyinfo::parse_key_infos;
+use crate::gpg::colons::{key_blocks, parse_key_blocks, parse_records, Key, Record, Validity};
 
-#[derive(Debug, Clone)]
-pub(crate) struct SshKeyInfo {
-    pub main_key_id: String,
-    pub main_name: String,
-    pub auth_fingerprint: String,
-    pub auth_keygrip: String,
+pub(super) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
 }
 
-impl SshKeyInfo {
-    fn new(
-        key_id: &str,
-        main_name: &str,
-        auth_fingerprint: &str,
-        auth_keygrip: &str,
-    ) -> Self {
-        SshKeyInfo {
-            main_key_id: key_id.to_string(),
-            main_name: main_name.to_string(),
-            auth_fingerprint: auth_fingerprint.to_string(),
-            auth_keygrip: auth_keygrip.to_string(),
+fn is_expired(validity: Validity, expires: Option<u64>) -> bool {
+    validity == Validity::Expired || expires.is_some_and(|expires| expires <= now())
+}
+
+/// Where the secret part of a key is
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SecretAvailability {
+    /// The secret key is in the local keyring
+    Local,
+    /// The secret key is on a smartcard, the local keyring only has a stub pointing at the card
+    Card { serial_number: String },
+    /// Only the public key is available (e.g. an imported key of a colleague or a `#` stub)
+    PublicOnly,
+}
+
+impl SecretAvailability {
+    /// Reads field 15 of a `sec`/`ssb` record
+    fn from_serial_number(serial_number: Option<&str>) -> Self {
+        match serial_number {
+            Some("+") => SecretAvailability::Local,
+            Some("#") | None => SecretAvailability::PublicOnly,
+            Some(serial_number) => SecretAvailability::Card { serial_number: serial_number.to_string() },
         }
     }
-    
-    pub(crate) fn auth_openpgp_hex_string(&self) -> &str {
-        &self.auth_fingerprint[(self.auth_fingerprint.len()-8)..]
+}
+
+impl Display for SecretAvailability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretAvailability::Local => write!(f, "local secret"),
+            SecretAvailability::Card { serial_number } => write!(f, "on card {serial_number}"),
+            SecretAvailability::PublicOnly => write!(f, "public only"),
+        }
     }
 }
 
+/// A subkey capable of authentication (`[A]`), which can be used as an SSH key
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthSubkey {
+    /// Fingerprint of the auth subkey
+    pub fingerprint: String,
+    /// Keygrip of the auth subkey, which is how gpg-agent refers to the key
+    pub keygrip: String,
+    /// Where the secret part of the auth subkey is
+    pub secret: SecretAvailability,
+    /// The algorithm of the auth subkey, e.g. `ed25519` or `rsa3072`
+    pub algorithm: String,
+    /// The validity of the auth subkey
+    pub validity: Validity,
+    /// Creation time of the auth subkey in seconds since epoch
+    pub created: Option<u64>,
+    /// Expiry time of the auth subkey in seconds since epoch, `None` if it does not expire
+    pub expires: Option<u64>,
+}
 
-pub(crate) fn gpg_keys(key_ids: Option<Vec<String>>) -> Vec<SshKeyInfo> {
-    let mut command = Command::new("gpg");
-    command.stdin(Stdio::null())
-        .arg("--list-keys")
-        .arg("--with-keygrip")
-        .arg("--with-colons");
+impl AuthSubkey {
+    /// The last 8 hex digits of the fingerprint, which is the `openpgp:0x...`
+    /// comment of the exported SSH key
+    pub fn openpgp_hex_string(&self) -> &str {
+        &self.fingerprint[(self.fingerprint.len()-8)..]
+    }
 
-    if let Some(key_ids) = key_ids {
-        for key_id in key_ids { command.arg(key_id);}
+    /// The key id telling `gpg --export-ssh-key` to export exactly this subkey
+    pub fn export_key_id(&self) -> String {
+        format!("{}!", self.fingerprint)
     }
 
-    let output = command.output()
-        .expect("Failed to execute command");
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.validity, self.expires)
+    }
 
-    let mut ssh_keys: Vec<SshKeyInfo> = Vec::new();
-    let mut line_count_key: usize = 0;
-    let mut line_sub_auth_detected = false;
-    
-    let mut main_key_id: Option<&str> = None;
-    let mut main_uid_name: Option<&str> = None;
-    let mut auth_fingerprint: Option<&str> = None;
-    let mut auth_keygrip: Option<&str> = None;
-
-    for (row_no, row) in String::from_utf8(output.stdout).expect(
-        "Failed at converting GPG (stdout) to String"
-    ).trim().split("\n").enumerate() {
-        trace!("row_no: {} - row: {}", row_no, row);
-        // println!("{:02}: {}", row_no, row);
-
-        if line_count_key >= 1 { line_count_key += 1 }
-
-        if row_no == 0 {
-            if get_type(row) == "tru" {
-                continue
-
-            } else {
-                error!("The 1st line from\
-                `gpg --list-keys --with-keygrip --with-colons`\
-                 did not start with 'tru'");
-                exit(1)
-            }
-        }
+    pub fn is_revoked(&self) -> bool {
+        self.validity == Validity::Revoked
+    }
+}
 
-        if get_type(row) == "pub" {
-            line_count_key = 1;
-            main_key_id = None;
-            main_uid_name = None;
-            auth_fingerprint = None;
-            auth_keygrip = None;
-        }
+/// Any subkey of a GPG key, whatever it can be used for
+#[derive(Debug, Clone, Serialize)]
+pub struct Subkey {
+    pub fingerprint: String,
+    /// The algorithm of the subkey, e.g. `ed25519` or `cv25519`
+    pub algorithm: String,
+    /// What the subkey can be used for, in the letters of gpg (e.g. `e` or `sa`)
+    pub capabilities: String,
+    pub validity: Validity,
+    /// Creation time in seconds since epoch
+    pub created: Option<u64>,
+    /// Expiry time in seconds since epoch, `None` if the subkey does not expire
+    pub expires: Option<u64>,
+}
 
-        if line_count_key == 2 {
-            if let Some(key_id) = get_fingerprint_of_key(row) { main_key_id = Some(key_id) }
+impl From<&Key> for Subkey {
+    fn from(key: &Key) -> Self {
+        Subkey {
+            fingerprint: key.fingerprint.clone(),
+            algorithm: key.record.algorithm_name(),
+            capabilities: key.record.capabilities.to_string(),
+            validity: key.record.validity,
+            created: key.record.created,
+            expires: key.record.expires,
         }
+    }
+}
 
-        if let Some(name) = get_name(row) {
-            main_uid_name = Some(name);
-        }
+/// A GPG key with its auth subkeys, [`gpg_keys`] only lists the keys with one or more of them
+#[derive(Debug, Clone, Serialize)]
+pub struct SshKeyInfo {
+    /// Fingerprint of the primary key
+    pub main_key_id: String,
+    /// The primary user id of the key (the first one gpg lists)
+    pub main_name: String,
+    /// Every user id of the key, starting with [`SshKeyInfo::main_name`]
+    pub user_ids: Vec<String>,
+    /// The validity of the primary key
+    pub main_validity: Validity,
+    /// Expiry time of the primary key in seconds since epoch, `None` if it does not expire
+    pub main_expires: Option<u64>,
+    /// The primary key has been disabled
+    pub main_disabled: bool,
+    /// The auth subkeys, in the order gpg lists them (oldest first)
+    pub auth_subkeys: Vec<AuthSubkey>,
+    /// Every subkey of the key, including the auth subkeys
+    pub subkeys: Vec<Subkey>,
+}
 
-        if line_sub_auth_detected == false && main_uid_name.is_some() {
-            line_sub_auth_detected = is_sub_auth_key(row);
-        }
+impl SshKeyInfo {
+    /// Whether the secret part of at least one of the auth subkeys is available
+    pub fn has_secret(&self) -> bool {
+        self.auth_subkeys.iter().any(|auth_subkey| auth_subkey.secret != SecretAvailability::PublicOnly)
+    }
 
-        if line_sub_auth_detected {
-            if let Some(fingerprint) = get_fingerprint_of_key(row) {
-                auth_fingerprint = Some(fingerprint)
-            }
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.main_validity, self.main_expires)
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.main_validity == Validity::Revoked
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.main_disabled || self.main_validity == Validity::Disabled
+    }
+
+    /// Why the auth subkey should not be used for SSH, `None` if it can be used
+    pub fn unusable_reason(&self, auth_subkey: &AuthSubkey) -> Option<&'static str> {
+        if self.is_revoked() {
+            Some("primary key is revoked")
+        } else if auth_subkey.is_revoked() {
+            Some("auth subkey is revoked")
+        } else if self.is_expired() {
+            Some("primary key has expired")
+        } else if auth_subkey.is_expired() {
+            Some("auth subkey has expired")
+        } else {
+            None
         }
+    }
 
-        if line_sub_auth_detected {
-            if let Some(fingerprint) = get_keygrip(row) {
-                auth_keygrip = Some(fingerprint)
-            }
+    /// How the auth subkey is named in messages, e.g. `Alice <alice@example.com> openpgp:0x7AD47E32`
+    pub fn describe(&self, auth_subkey: &AuthSubkey) -> String {
+        format!("{} openpgp:0x{}", self.main_name, auth_subkey.openpgp_hex_string())
+    }
+
+    /// Fails if the auth subkey is expired or revoked, unless `force` is set, then it only warns
+    pub fn ensure_usable(&self, auth_subkey: &AuthSubkey, force: bool) -> Result<()> {
+        let Some(reason) = self.unusable_reason(auth_subkey) else { return Ok(()) };
+        let key = self.describe(auth_subkey);
+
+        if force {
+            warn!("Using the key {key} even though the {reason}");
+            Ok(())
+        } else {
+            Err(GpgSshError::UnusableKey { key, reason: reason.to_string() })
         }
+    }
+}
+
+/// Drops the auth subkeys without a secret key, and the keys left without any auth subkeys
+///
+/// Those keys are usually keys of others, imported to verify signatures,
+/// which gpg-agent cannot use for SSH anyway.
+pub fn without_public_only(ssh_keys: Vec<SshKeyInfo>) -> Vec<SshKeyInfo> {
+    ssh_keys.into_iter().filter_map(|mut ssh_key| {
+        ssh_key.auth_subkeys.retain(|auth_subkey| auth_subkey.secret != SecretAvailability::PublicOnly);
+        Some(ssh_key).filter(|ssh_key| !ssh_key.auth_subkeys.is_empty())
+    }).collect()
+}
 
-        if let Some(auth_keygrip) = auth_keygrip {
-            if let Some(auth_fingerprint) = auth_fingerprint {
-                if let Some(main_key_id) = main_key_id {
-                    if let Some(main_name) = main_uid_name {
-                        ssh_keys.push(SshKeyInfo::new(
-                            main_key_id, main_name, auth_fingerprint, auth_keygrip
-                        ));
-                        line_sub_auth_detected = false;
-                    }
-                }
+/// A primary key with its secret key, to which subkeys can be added
+#[derive(Debug, Clone, Serialize)]
+pub struct SecretKey {
+    pub fingerprint: String,
+    /// The primary user id of the key (the first one gpg lists)
+    pub name: String,
+    /// The algorithm of the primary key, e.g. `ed25519` or `rsa3072`
+    pub algorithm: String,
+    pub validity: Validity,
+    /// Expiry time in seconds since epoch, `None` if the key does not expire
+    pub expires: Option<u64>,
+    /// Whether the secret key is local or on a card, never [`SecretAvailability::PublicOnly`]
+    pub secret: SecretAvailability,
+}
+
+impl SecretKey {
+    /// How the key is named in messages, e.g. `Alice <alice@example.com> (0E42E055...)`
+    pub fn describe(&self) -> String {
+        format!("{} ({})", self.name, self.fingerprint)
+    }
+}
+
+/// Lists the primary keys whose secret key is available, with or without auth subkeys
+///
+/// Keys whose secret primary key is only a stub (`#`) are skipped, as gpg cannot add subkeys to them.
+pub fn secret_primary_keys(backend: &dyn GpgBackend) -> Result<Vec<SecretKey>> {
+    let stdout = backend.list_secret_keys(&[])?;
+
+    let mut secret_keys = Vec::new();
+    for block in parse_key_blocks(&stdout)? {
+        let Some(uid) = block.uids.first() else { continue };
+        let record = &block.primary.record;
+        let secret = SecretAvailability::from_serial_number(record.serial_number.as_deref());
+        if secret == SecretAvailability::PublicOnly { continue }
+
+        secret_keys.push(SecretKey {
+            fingerprint: block.primary.fingerprint.clone(),
+            name: uid.user_id.clone(),
+            algorithm: record.algorithm_name(),
+            validity: record.validity,
+            expires: record.expires,
+            secret,
+        });
+    }
+
+    Ok(secret_keys)
+}
+
+/// The secret availability of every key in the secret keyring, by keygrip
+fn secret_availability(backend: &dyn GpgBackend) -> Result<HashMap<String, SecretAvailability>> {
+    // All the secret keys are listed, because gpg fails if one of the key ids has no secret key
+    let stdout = backend.list_secret_keys(&[])?;
+
+    let mut availability = HashMap::new();
+    for block in parse_key_blocks(&stdout)? {
+        for key in std::iter::once(&block.primary).chain(block.subkeys.iter()) {
+            if let Some(keygrip) = &key.keygrip {
+                availability.insert(
+                    keygrip.clone(),
+                    SecretAvailability::from_serial_number(key.record.serial_number.as_deref()),
+                );
             }
         }
     }
 
-    ssh_keys
+    Ok(availability)
 }
 
-pub(crate) fn get_enabled_keygrip() -> HashSet<String> {
-    let mut command = Command::new("gpg-connect-agent");
-    command.stdin(Stdio::null())
-        .arg("KEYINFO --list --need-attr=Use-for-ssh");
-    let output = command
-        .output()
-        .expect("Failed to execute command");
+/// Lists the GPG keys with an auth subkey
+///
+/// If `key_ids` is given, only those keys are listed, otherwise all the keys in the keyring.
+/// The public keyring is cross-referenced with the secret keyring, see [`AuthSubkey::secret`]
+/// and [`without_public_only`].
+pub fn gpg_keys(backend: &dyn GpgBackend, key_ids: Option<Vec<String>>) -> Result<Vec<SshKeyInfo>> {
+    let mut ssh_keys = all_keys(backend, key_ids)?;
+    ssh_keys.retain(|ssh_key| !ssh_key.auth_subkeys.is_empty());
+
+    Ok(ssh_keys)
+}
+
+/// Lists the GPG keys like [`gpg_keys`], but also those without an auth subkey
+pub fn all_keys(backend: &dyn GpgBackend, key_ids: Option<Vec<String>>) -> Result<Vec<SshKeyInfo>> {
+    let stdout = backend.list_keys(&key_ids.unwrap_or_default())?;
+    let records = parse_records(&stdout)?;
+
+    if !matches!(records.first(), Some(Record::Trust(_))) {
+        return Err(GpgSshError::Parse(
+            "The 1st line from `gpg --list-keys --with-keygrip --with-colons` \
+            did not start with 'tru'".to_string()
+        ))
+    }
+
+    let secret_availability = secret_availability(backend)?;
+
+    let mut ssh_keys: Vec<SshKeyInfo> = Vec::new();
+    for block in key_blocks(records)? {
+        trace!("key block: {:?}", block);
+        let Some(uid) = block.uids.first() else { continue };
+
+        let auth_subkeys: Vec<AuthSubkey> = block.subkeys.iter()
+            .filter(|subkey| subkey.record.capabilities.authenticate)
+            .filter_map(|subkey| {
+                let keygrip = subkey.keygrip.clone()?;
+                Some(AuthSubkey {
+                    fingerprint: subkey.fingerprint.clone(),
+                    algorithm: subkey.record.algorithm_name(),
+                    validity: subkey.record.validity,
+                    created: subkey.record.created,
+                    expires: subkey.record.expires,
+                    secret: secret_availability.get(&keygrip).cloned()
+                        .unwrap_or(SecretAvailability::PublicOnly),
+                    keygrip,
+                })
+            })
+            .collect();
+
+        ssh_keys.push(SshKeyInfo {
+            main_key_id: block.primary.fingerprint.clone(),
+            main_name: uid.user_id.clone(),
+            user_ids: block.uids.iter().map(|uid| uid.user_id.clone()).collect(),
+            main_validity: block.primary.record.validity,
+            main_expires: block.primary.record.expires,
+            main_disabled: block.primary.record.disabled,
+            auth_subkeys,
+            subkeys: block.subkeys.iter().map(Subkey::from).collect(),
+        });
+    }
+
+    Ok(ssh_keys)
+}
+
+/// The keygrips gpg-agent has marked with `Use-for-ssh`
+pub fn get_enabled_keygrip(backend: &dyn GpgBackend) -> Result<HashSet<String>> {
+    let response = backend.agent_command("KEYINFO --list --need-attr=Use-for-ssh")?;
+
+    Ok(parse_key_infos(&response)?.into_iter().map(|key_info| key_info.keygrip).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpg::MockBackend;
+
+    const LIST_KEYS: &str = include_str!("testdata/list_keys.txt");
+
+    #[test]
+    fn gpg_keys_only_returns_keys_with_auth_subkey() {
+        let backend = MockBackend::new(LIST_KEYS);
+        let keys = gpg_keys(&backend, None).unwrap();
+
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].main_key_id, "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(keys[0].main_name, "Alice (SSH Key) <alice@example.com>");
+        assert_eq!(keys[0].auth_subkeys.len(), 1);
+        assert_eq!(keys[0].auth_subkeys[0].fingerprint, "5BCC596B65ED955F64C31BBC0C2E24E97AD47E32");
+        assert_eq!(keys[0].auth_subkeys[0].keygrip, "0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243");
+        assert_eq!(keys[0].auth_subkeys[0].openpgp_hex_string(), "7AD47E32");
+        assert_eq!(keys[0].auth_subkeys[0].export_key_id(), "5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!");
+    }
+
+    #[test]
+    fn all_keys_also_returns_keys_without_auth_subkey() {
+        let backend = MockBackend::new(LIST_KEYS);
+        let keys = all_keys(&backend, None).unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].main_name, "Bob (SSH Key) <bob@example.com>");
+        assert!(keys[1].auth_subkeys.is_empty());
+    }
+
+    #[test]
+    fn gpg_keys_keeps_every_auth_subkey() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys_two_auth.txt"));
+        let keys = gpg_keys(&backend, None).unwrap();
 
-    cmd_error_handler(&command, &output);
+        assert_eq!(keys.len(), 1);
+        let subkeys: Vec<(&str, &str)> = keys[0].auth_subkeys.iter()
+            .map(|subkey| (subkey.fingerprint.as_str(), subkey.keygrip.as_str()))
+            .collect();
+        assert_eq!(subkeys, vec![
+            ("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32", "0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243"),
+            ("2D7E8C21F36DC2BC308110C43A543B3BB5E24591", "74E85BD09EB34D98EE2AC0F717B384B8E28DF93F"),
+        ]);
+    }
+
+    #[test]
+    fn gpg_keys_lists_user_ids_and_subkeys() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys_mixed_secret.txt"));
+        let keys = gpg_keys(&backend, None).unwrap();
+
+        assert_eq!(keys[0].user_ids, vec!["Alice (SSH Key) <alice@example.com>"]);
+        let subkeys: Vec<(&str, &str, &str)> = keys[0].subkeys.iter()
+            .map(|subkey| (&subkey.fingerprint[32..], subkey.algorithm.as_str(), subkey.capabilities.as_str()))
+            .collect();
+        assert_eq!(subkeys, vec![
+            ("9D916D66", "ed25519", "s"),
+            ("A085BAFB", "cv25519", "e"),
+            ("7AD47E32", "ed25519", "a"),
+            ("B5E24591", "ed25519", "a"),
+        ]);
+        assert_eq!(keys[0].auth_subkeys[1].algorithm, "ed25519");
+        assert_eq!(keys[0].auth_subkeys[1].created, Some(1792314705));
+    }
+
+    #[test]
+    fn serialised_key_has_its_auth_subkeys() {
+        let backend = MockBackend::new(LIST_KEYS);
+        let keys = gpg_keys(&backend, None).unwrap();
+
+        let json = serde_json::to_value(&keys[0]).unwrap();
+        assert_eq!(json["auth_subkeys"][0]["keygrip"], "0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243");
+    }
 
-    let mut keygrip = HashSet::new();
-    let stdout = String::from_utf8(output.stdout).unwrap();
-    let rows = stdout.trim().lines();
-    for row in rows {
-        if row == "OK" { break }
-        let mut columns = row.split(" ");
-        let key = columns.nth(2).unwrap();
-        keygrip.insert(key.to_owned());
+    #[test]
+    fn secret_primary_keys_skip_stubs() {
+        let secret_keys = include_str!("testdata/list_secret_keys.txt");
+        let backend = MockBackend::new("").with_list_secret_keys_output(secret_keys);
+        let keys = secret_primary_keys(&backend).unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].fingerprint, "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(keys[0].name, "Alice (SSH Key) <alice@example.com>");
+        assert_eq!(keys[0].secret, SecretAvailability::Local);
+
+        let backend = MockBackend::new("").with_list_secret_keys_output(
+            &secret_keys.replace("sec:u:255:22:510DF3503748F565:1792314498:::u:::cESCA:::+:", "sec:u:255:22:510DF3503748F565:1792314498:::u:::cESCA:::#:")
+        );
+        let keys = secret_primary_keys(&backend).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].fingerprint, "75D982CDE8656E2D7E7AFCB8387E23CF4DAE23F1");
     }
 
-    keygrip
+    #[test]
+    fn gpg_keys_passes_key_ids_on() {
+        let backend = MockBackend::new(LIST_KEYS);
+        gpg_keys(&backend, Some(vec!["0E42E055657C72FFB213CF83510DF3503748F565".to_string()])).unwrap();
+
+        assert_eq!(backend.calls(), vec!["--list-keys 0E42E055657C72FFB213CF83510DF3503748F565", "--list-secret-keys"]);
+    }
+
+    #[test]
+    fn gpg_keys_fails_without_tru_line() {
+        let backend = MockBackend::new(LIST_KEYS.split_once('\n').unwrap().1);
+
+        assert!(matches!(gpg_keys(&backend, None), Err(GpgSshError::Parse(_))));
+    }
+
+    #[test]
+    fn gpg_keys_marks_secret_availability() {
+        let secret_keys = include_str!("testdata/list_secret_keys.txt").replace(
+            "ssb:u:255:22:3A543B3BB5E24591:1792314705::::::a:::+:",
+            "ssb:u:255:22:3A543B3BB5E24591:1792314705::::::a:::D2760001240103040006123456780000:",
+        );
+        let backend = MockBackend::new(include_str!("testdata/list_keys_mixed_secret.txt"))
+            .with_list_secret_keys_output(&secret_keys);
+        let keys = gpg_keys(&backend, None).unwrap();
+
+        let secrets: Vec<(&str, &SecretAvailability)> = keys.iter()
+            .flat_map(|key| key.auth_subkeys.iter())
+            .map(|subkey| (subkey.openpgp_hex_string(), &subkey.secret))
+            .collect();
+        assert_eq!(secrets, vec![
+            ("7AD47E32", &SecretAvailability::PublicOnly),
+            ("B5E24591", &SecretAvailability::Card { serial_number: "D2760001240103040006123456780000".to_string() }),
+            ("2DDE6F8F", &SecretAvailability::PublicOnly),
+        ]);
+        assert!(keys[0].has_secret());
+        assert!(!keys[1].has_secret());
+
+        let keys = without_public_only(keys);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].main_name, "Alice (SSH Key) <alice@example.com>");
+        assert_eq!(keys[0].auth_subkeys.len(), 1);
+        assert_eq!(keys[0].auth_subkeys[0].openpgp_hex_string(), "B5E24591");
+    }
+
+    #[test]
+    fn gpg_keys_reads_validity_and_expiry() {
+        let list_keys = LIST_KEYS
+            .replace("pub:u:255:22:510DF3503748F565:1792314498:::u:::cESCA:", "pub:u:255:22:510DF3503748F565:1792314498:4102444800::u:::cESCAD:")
+            .replace("sub:u:255:22:0C2E24E97AD47E32:1792314498::", "sub:e:255:22:0C2E24E97AD47E32:1792314498:1792400898:");
+        let backend = MockBackend::new(&list_keys);
+        let keys = gpg_keys(&backend, None).unwrap();
+
+        assert_eq!(keys[0].main_validity, Validity::Ultimate);
+        assert_eq!(keys[0].main_expires, Some(4102444800));
+        assert!(keys[0].is_disabled());
+        assert!(!keys[0].is_expired());
+        let auth_subkey = &keys[0].auth_subkeys[0];
+        assert_eq!(auth_subkey.validity, Validity::Expired);
+        assert_eq!(auth_subkey.expires, Some(1792400898));
+        assert!(auth_subkey.is_expired());
+        assert_eq!(keys[0].unusable_reason(auth_subkey), Some("auth subkey has expired"));
+        assert!(matches!(keys[0].ensure_usable(auth_subkey, false), Err(GpgSshError::UnusableKey { .. })));
+        assert!(keys[0].ensure_usable(auth_subkey, true).is_ok());
+    }
+
+    #[test]
+    fn revoked_primary_key_is_unusable() {
+        let list_keys = LIST_KEYS
+            .replace("pub:u:255:22:510DF3503748F565", "pub:r:255:22:510DF3503748F565");
+        let backend = MockBackend::new(&list_keys);
+        let keys = gpg_keys(&backend, None).unwrap();
+
+        assert!(keys[0].is_revoked());
+        assert_eq!(keys[0].unusable_reason(&keys[0].auth_subkeys[0]), Some("primary key is revoked"));
+    }
+
+    #[test]
+    fn get_enabled_keygrip_parses_keyinfo() {
+        let backend = MockBackend::new("").with_agent_response(
+            "KEYINFO --list --need-attr=Use-for-ssh",
+            "S KEYINFO 0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243 D - - - P - - -\nOK\n",
+        );
+
+        assert_eq!(
+            get_enabled_keygrip(&backend).unwrap(),
+            HashSet::from(["0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243".to_string()]),
+        );
+    }
 }
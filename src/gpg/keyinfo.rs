@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use log::warn;
+use serde::Serialize;
+use crate::error::{GpgSshError, Result};
+use super::assuan::AgentResponse;
+use super::backend::GpgBackend;
+
+/// Where gpg-agent keeps the secret key (the type field of KEYINFO)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStorage {
+    /// `D`: A regular key stored on disk
+    Disk,
+    /// `T`: A key stored on a smartcard (token)
+    Smartcard,
+    /// `X`: Unknown type, or the key is missing
+    Unknown,
+}
+
+/// Whether the secret key is protected by a passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protection {
+    /// `P`: Protected by a passphrase
+    Protected,
+    /// `C`: Not protected
+    Clear,
+    /// `-`: Unknown (e.g. keys on a smartcard)
+    Unknown,
+}
+
+/// The flags field of KEYINFO
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct KeyInfoFlags {
+    /// `D`: The key has been disabled in `sshcontrol`
+    pub disabled: bool,
+    /// `S`: The key is listed in `sshcontrol`
+    pub in_sshcontrol: bool,
+    /// `c`: Using the key for SSH has to be confirmed
+    pub confirm: bool,
+}
+
+/// A `S KEYINFO <keygrip> <type> <serialno> <idstr> <cached> <protection> <fpr> <ttl> <flags>` line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyInfo {
+    pub keygrip: String,
+    pub storage: KeyStorage,
+    /// The serial number of the smartcard holding the key
+    pub serial_number: Option<String>,
+    /// The id of the key on the smartcard, e.g. `OPENPGP.3`
+    pub id_str: Option<String>,
+    /// The passphrase of the key is in the cache of gpg-agent
+    pub cached: bool,
+    pub protection: Protection,
+    /// The SSH fingerprint of the key, only listed with `--ssh-fpr`
+    pub ssh_fingerprint: Option<String>,
+    /// The TTL in seconds from `sshcontrol`, only listed with `--with-ssh` or `--ssh-list`
+    pub ttl: Option<u32>,
+    pub flags: KeyInfoFlags,
+}
+
+/// `-` stands for an empty field
+fn optional(field: &str) -> Option<String> {
+    Some(field).filter(|field| *field != "-").map(str::to_string)
+}
+
+impl KeyInfo {
+    /// Parses the arguments of a `S KEYINFO` line, i.e. everything after `KEYINFO`
+    pub fn parse(args: &str) -> Result<Self> {
+        let fields: Vec<&str> = args.split(' ').collect();
+        let error = || GpgSshError::Parse(format!("Expected `<keygrip> <type> ...` in the KEYINFO line: {args}"));
+        let field = |index: usize| fields.get(index).copied().unwrap_or("-");
+        let keygrip = fields.first().filter(|keygrip| !keygrip.is_empty()).ok_or_else(error)?;
+
+        let storage = match field(1) {
+            "D" => KeyStorage::Disk,
+            "T" => KeyStorage::Smartcard,
+            "X" | "-" => KeyStorage::Unknown,
+            _ => return Err(error()),
+        };
+        let protection = match field(5) {
+            "P" => Protection::Protected,
+            "C" => Protection::Clear,
+            _ => Protection::Unknown,
+        };
+        let ttl = optional(field(7)).map(|ttl| ttl.parse().map_err(|_| error())).transpose()?;
+        let flags = field(8);
+
+        Ok(KeyInfo {
+            keygrip: keygrip.to_string(),
+            storage,
+            serial_number: optional(field(2)),
+            id_str: optional(field(3)),
+            cached: field(4) == "1",
+            protection,
+            ssh_fingerprint: optional(field(6)),
+            ttl,
+            flags: KeyInfoFlags {
+                disabled: flags.contains('D'),
+                in_sshcontrol: flags.contains('S'),
+                confirm: flags.contains('c'),
+            },
+        })
+    }
+}
+
+/// Parses the `S KEYINFO` lines of the response
+pub(super) fn parse_key_infos(response: &AgentResponse) -> Result<Vec<KeyInfo>> {
+    response.status_args("KEYINFO").map(KeyInfo::parse).collect()
+}
+
+/// What gpg-agent knows about each of its secret keys, by keygrip
+pub fn key_infos(backend: &dyn GpgBackend) -> Result<HashMap<String, KeyInfo>> {
+    let response = backend.agent_command("KEYINFO --list --with-ssh --ssh-fpr")?;
+
+    Ok(parse_key_infos(&response)?.into_iter()
+        .map(|key_info| (key_info.keygrip.clone(), key_info))
+        .collect())
+}
+
+/// [`key_infos`], or nothing (with a warning) if gpg-agent cannot be asked, for listings that show the keys anyway
+pub fn agent_key_infos(backend: &dyn GpgBackend) -> HashMap<String, KeyInfo> {
+    key_infos(backend).unwrap_or_else(|err| {
+        warn!("Could not ask gpg-agent about the keys: {err}");
+        HashMap::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpg::MockBackend;
+
+    #[test]
+    fn parse_disk_key() {
+        let key_info = KeyInfo::parse(
+            "74E85BD09EB34D98EE2AC0F717B384B8E28DF93F D - - 1 P MD5:a3:be:4e:a4:b0:2d:3b:ff:84:d2:2c:84:d5:fd:95:c2 600 Sc"
+        ).unwrap();
+
+        assert_eq!(key_info, KeyInfo {
+            keygrip: "74E85BD09EB34D98EE2AC0F717B384B8E28DF93F".to_string(),
+            storage: KeyStorage::Disk,
+            serial_number: None,
+            id_str: None,
+            cached: true,
+            protection: Protection::Protected,
+            ssh_fingerprint: Some("MD5:a3:be:4e:a4:b0:2d:3b:ff:84:d2:2c:84:d5:fd:95:c2".to_string()),
+            ttl: Some(600),
+            flags: KeyInfoFlags { disabled: false, in_sshcontrol: true, confirm: true },
+        });
+    }
+
+    #[test]
+    fn parse_smartcard_key() {
+        let key_info = KeyInfo::parse("0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243 T D2760001240103040006123456780000 OPENPGP.3 - - - - -").unwrap();
+
+        assert_eq!(key_info.storage, KeyStorage::Smartcard);
+        assert_eq!(key_info.serial_number.as_deref(), Some("D2760001240103040006123456780000"));
+        assert_eq!(key_info.id_str.as_deref(), Some("OPENPGP.3"));
+        assert!(!key_info.cached);
+        assert_eq!(key_info.protection, Protection::Unknown);
+        assert_eq!(key_info.flags, KeyInfoFlags::default());
+    }
+
+    #[test]
+    fn parse_short_and_invalid_lines() {
+        assert_eq!(KeyInfo::parse("AAAA D").unwrap().storage, KeyStorage::Disk);
+        assert!(KeyInfo::parse("").is_err());
+        assert!(KeyInfo::parse("AAAA Q").is_err());
+        assert!(KeyInfo::parse("AAAA D - - - P - soon -").is_err());
+    }
+
+    #[test]
+    fn key_infos_by_keygrip() {
+        let backend = MockBackend::new("").with_agent_response(
+            "KEYINFO --list --with-ssh --ssh-fpr",
+            "S KEYINFO AAAA D - - 1 P - - -\nS KEYINFO BBBB T 1234 OPENPGP.3 - - - - -\nOK\n",
+        );
+        let key_infos = key_infos(&backend).unwrap();
+
+        assert!(key_infos["AAAA"].cached);
+        assert_eq!(key_infos["BBBB"].storage, KeyStorage::Smartcard);
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use crate::error::{GpgSshError, Result};
+use crate::ssh::SshPublicKey;
+use super::backend::GpgBackend;
+use super::copy_id::ssh_public_keys;
+use super::info::{AuthSubkey, SecretKey, SshKeyInfo, Subkey};
+
+/// The auth subkeys matching the query, see [`find_auth_subkey`]
+pub fn match_auth_subkeys<'a>(ssh_keys: &'a [SshKeyInfo], query: &str) -> Vec<(&'a SshKeyInfo, &'a AuthSubkey)> {
+    let hex_id = KeyQuery::hex_id(query);
+    let uid = query.to_lowercase();
+
+    ssh_keys.iter().flat_map(|ssh_key| {
+        ssh_key.auth_subkeys.iter().map(move |auth_subkey| (ssh_key, auth_subkey))
+    }).filter(|(ssh_key, auth_subkey)| match &hex_id {
+        Some(hex_id) => hex_id.matches_fingerprint(&ssh_key.main_key_id)
+            || hex_id.matches_fingerprint(&auth_subkey.fingerprint)
+            || hex_id.matches_keygrip(&auth_subkey.keygrip),
+        None => ssh_key.main_name.to_lowercase().contains(&uid),
+    }).collect()
+}
+
+/// Finds the auth subkey the query refers to
+///
+/// The query can be the fingerprint or the long (16 hex) or short (8 hex) key id of the primary key or the auth subkey,
+/// the keygrip, the `openpgp:0x...` id of the SSH key or a part of the user id.
+/// It is an error if nothing or more than one auth subkey matches.
+pub fn find_auth_subkey<'a>(ssh_keys: &'a [SshKeyInfo], query: &str) -> Result<(&'a SshKeyInfo, &'a AuthSubkey)> {
+    let matches = match_auth_subkeys(ssh_keys, query);
+
+    match matches.as_slice() {
+        [] => Err(GpgSshError::KeyNotFound(query.to_string())),
+        [found] => Ok(*found),
+        _ => Err(GpgSshError::AmbiguousKey {
+            query: query.to_string(),
+            candidates: matches.iter().map(|(ssh_key, auth_subkey)| {
+                format!("{} openpgp:0x{} ({})", ssh_key.main_name, auth_subkey.openpgp_hex_string(), auth_subkey.fingerprint)
+            }).collect(),
+        }),
+    }
+}
+
+/// Finds the secret primary key the query refers to
+///
+/// The query can be the fingerprint, the long (16 hex) or short (8 hex) key id, or a part of the user id.
+/// It is an error if nothing or more than one key matches.
+pub fn find_secret_key<'a>(secret_keys: &'a [SecretKey], query: &str) -> Result<&'a SecretKey> {
+    let hex_id = KeyQuery::hex_id(query);
+    let uid = query.to_lowercase();
+
+    let matches: Vec<&SecretKey> = secret_keys.iter().filter(|secret_key| match &hex_id {
+        Some(hex_id) => hex_id.matches_fingerprint(&secret_key.fingerprint),
+        None => secret_key.name.to_lowercase().contains(&uid),
+    }).collect();
+
+    match matches.as_slice() {
+        [] => Err(GpgSshError::KeyNotFound(query.to_string())),
+        [found] => Ok(found),
+        _ => Err(GpgSshError::AmbiguousKey {
+            query: query.to_string(),
+            candidates: matches.iter().map(|secret_key| secret_key.describe()).collect(),
+        }),
+    }
+}
+
+/// An id of a key as gpg, ssh or a server log shows it, the format is detected by [`KeyQuery::from_str`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyQuery {
+    /// The last 8 hex digits of a fingerprint, e.g. `openpgp:0x7AD47E32` or `7AD47E32`
+    ShortKeyId(String),
+    /// The last 16 hex digits of a fingerprint, the long key id of gpg
+    LongKeyId(String),
+    /// 40 hex digits, which is either a fingerprint or a keygrip (they look the same)
+    FingerprintOrKeygrip(String),
+    /// The fingerprint of `ssh-keygen -l`, e.g. `SHA256:V3A7MNo4...`
+    SshSha256(String),
+    /// The legacy fingerprint of `ssh-keygen -l -E md5`, e.g. `MD5:d2:fa:51:...`
+    SshMd5(String),
+}
+
+impl FromStr for KeyQuery {
+    type Err = GpgSshError;
+
+    fn from_str(query: &str) -> Result<Self> {
+        let query = query.trim();
+        if let Some(fingerprint) = query.strip_prefix("SHA256:") {
+            // ssh-keygen leaves out the base64 padding
+            return Ok(KeyQuery::SshSha256(format!("SHA256:{}", fingerprint.trim_end_matches('='))));
+        }
+        if let Some(fingerprint) = query.strip_prefix("MD5:") {
+            return Ok(KeyQuery::SshMd5(format!("MD5:{}", fingerprint.to_lowercase())));
+        }
+
+        let hex = query.trim_start_matches("openpgp:");
+        let hex = hex.strip_prefix("0x").or_else(|| hex.strip_prefix("0X")).unwrap_or(hex).to_uppercase();
+        if !hex.chars().all(|char| char.is_ascii_hexdigit()) {
+            return Err(GpgSshError::InvalidArgument(format!(
+                "`{query}` is not a key id: expected 8, 16 or 40 hex digits (key id, fingerprint or keygrip), \
+                SHA256:... or MD5:..."
+            )));
+        }
+        match hex.len() {
+            8 => Ok(KeyQuery::ShortKeyId(hex)),
+            16 => Ok(KeyQuery::LongKeyId(hex)),
+            40 => Ok(KeyQuery::FingerprintOrKeygrip(hex)),
+            length => Err(GpgSshError::InvalidArgument(format!(
+                "`{query}` has {length} hex digits, a key id has 8 or 16, a fingerprint or keygrip 40"
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyQuery::ShortKeyId(hex) | KeyQuery::LongKeyId(hex) => write!(f, "0x{hex}"),
+            KeyQuery::FingerprintOrKeygrip(hex) => write!(f, "{hex}"),
+            KeyQuery::SshSha256(fingerprint) | KeyQuery::SshMd5(fingerprint) => write!(f, "{fingerprint}"),
+        }
+    }
+}
+
+impl KeyQuery {
+    /// The query as a key id, fingerprint or keygrip, `None` for anything else (e.g. a part of a user id)
+    ///
+    /// A hex id is never taken as a part of a user id, even if one contains it.
+    fn hex_id(query: &str) -> Option<Self> {
+        query.parse().ok().filter(|query| !matches!(query, KeyQuery::SshSha256(_) | KeyQuery::SshMd5(_)))
+    }
+
+    /// Whether the fingerprint is or ends with the key id, long and short key ids are the end of the fingerprint
+    fn matches_fingerprint(&self, fingerprint: &str) -> bool {
+        match self {
+            KeyQuery::ShortKeyId(hex) | KeyQuery::LongKeyId(hex) | KeyQuery::FingerprintOrKeygrip(hex) => {
+                fingerprint.ends_with(hex.as_str())
+            },
+            KeyQuery::SshSha256(_) | KeyQuery::SshMd5(_) => false,
+        }
+    }
+
+    fn matches_keygrip(&self, keygrip: &str) -> bool {
+        matches!(self, KeyQuery::FingerprintOrKeygrip(hex) if hex == keygrip)
+    }
+
+    fn matches_ssh_public_key(&self, ssh_public_key: &SshPublicKey) -> bool {
+        match self {
+            KeyQuery::SshSha256(fingerprint) => ssh_public_key.fingerprint_sha256() == *fingerprint,
+            KeyQuery::SshMd5(fingerprint) => ssh_public_key.fingerprint_md5() == *fingerprint,
+            _ => false,
+        }
+    }
+}
+
+/// A key found by [`lookup_keys`]
+#[derive(Debug, Clone, Copy)]
+pub struct KeyMatch<'a> {
+    pub ssh_key: &'a SshKeyInfo,
+    /// The subkey the query names, `None` if it names the primary key
+    pub subkey: Option<&'a Subkey>,
+}
+
+impl<'a> KeyMatch<'a> {
+    /// The auth subkey the query names, `None` for the primary key and the other subkeys
+    pub fn auth_subkey(&self) -> Option<&'a AuthSubkey> {
+        let subkey = self.subkey?;
+        self.ssh_key.auth_subkeys.iter().find(|auth_subkey| auth_subkey.fingerprint == subkey.fingerprint)
+    }
+}
+
+/// Finds the primary keys and subkeys the query names
+///
+/// Key ids and fingerprints are compared with the primary keys and all their subkeys, keygrips and
+/// SSH fingerprints with the auth subkeys. Only SSH fingerprints need the public keys to be exported,
+/// the auth subkeys gpg cannot export for SSH do not match them. Pass the keys of
+/// [`all_keys`](super::all_keys) to also find the keys without an auth subkey.
+pub fn lookup_keys<'a>(backend: &dyn GpgBackend, ssh_keys: &'a [SshKeyInfo], query: &KeyQuery) -> Result<Vec<KeyMatch<'a>>> {
+    let ssh_public_keys = match query {
+        KeyQuery::SshSha256(_) | KeyQuery::SshMd5(_) => ssh_public_keys(backend, ssh_keys)?,
+        _ => HashMap::new(),
+    };
+
+    let mut matches = Vec::new();
+    for ssh_key in ssh_keys {
+        if query.matches_fingerprint(&ssh_key.main_key_id) {
+            matches.push(KeyMatch { ssh_key, subkey: None });
+        }
+        for subkey in &ssh_key.subkeys {
+            let auth_subkey = ssh_key.auth_subkeys.iter().find(|auth_subkey| auth_subkey.fingerprint == subkey.fingerprint);
+            let matches_auth_subkey = auth_subkey.is_some_and(|auth_subkey| {
+                query.matches_keygrip(&auth_subkey.keygrip)
+                    || ssh_public_keys.get(&auth_subkey.keygrip)
+                        .is_some_and(|ssh_public_key| ssh_public_key.as_ref().is_ok_and(|ssh_public_key| query.matches_ssh_public_key(ssh_public_key)))
+            });
+            if query.matches_fingerprint(&subkey.fingerprint) || matches_auth_subkey {
+                matches.push(KeyMatch { ssh_key, subkey: Some(subkey) });
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
+/// Finds the auth subkey of an SSH public key, e.g. a line of `authorized_keys`
+///
+/// The key blobs are compared, so the comment does not matter. `ssh_public_keys` comes from
+/// [`ssh_public_keys`](super::ssh_public_keys), the auth subkeys gpg could not export are never found.
+pub fn find_ssh_public_key<'a>(
+    ssh_keys: &'a [SshKeyInfo],
+    ssh_public_keys: &HashMap<String, Result<SshPublicKey>>,
+    ssh_public_key: &SshPublicKey,
+) -> Option<(&'a SshKeyInfo, &'a AuthSubkey)> {
+    ssh_keys.iter().flat_map(|ssh_key| {
+        ssh_key.auth_subkeys.iter().map(move |auth_subkey| (ssh_key, auth_subkey))
+    }).find(|(_, auth_subkey)| {
+        ssh_public_keys.get(&auth_subkey.keygrip)
+            .is_some_and(|known| known.as_ref().is_ok_and(|known| known.blob == ssh_public_key.blob))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpg::{all_keys, gpg_keys, secret_primary_keys, MockBackend};
+
+    fn ssh_keys() -> Vec<SshKeyInfo> {
+        let backend = MockBackend::new(include_str!("testdata/list_keys_mixed_secret.txt"));
+        gpg_keys(&backend, None).unwrap()
+    }
+
+    fn find_hex(ssh_keys: &[SshKeyInfo], query: &str) -> Result<String> {
+        find_auth_subkey(ssh_keys, query).map(|(_, auth_subkey)| auth_subkey.openpgp_hex_string().to_string())
+    }
+
+    #[test]
+    fn find_by_identifiers() {
+        let ssh_keys = ssh_keys();
+
+        assert_eq!(find_hex(&ssh_keys, "F70C8FBB9C5868EC820F42A72AEDBD63D585271B").unwrap(), "2DDE6F8F");
+        assert_eq!(find_hex(&ssh_keys, "2d7e8c21f36dc2bc308110c43a543b3bb5e24591").unwrap(), "B5E24591");
+        assert_eq!(find_hex(&ssh_keys, "0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243").unwrap(), "7AD47E32");
+        assert_eq!(find_hex(&ssh_keys, "openpgp:0x7AD47E32").unwrap(), "7AD47E32");
+        assert_eq!(find_hex(&ssh_keys, "0xb5e24591").unwrap(), "B5E24591");
+        assert_eq!(find_hex(&ssh_keys, "bob@").unwrap(), "2DDE6F8F");
+    }
+
+    #[test]
+    fn detect_query_format() {
+        let query = |query: &str| query.parse::<KeyQuery>();
+
+        assert_eq!(query("openpgp:0x7ad47e32").unwrap(), KeyQuery::ShortKeyId("7AD47E32".to_string()));
+        assert_eq!(query("0x3762ED355835E8BE").unwrap(), KeyQuery::LongKeyId("3762ED355835E8BE".to_string()));
+        assert!(matches!(query("15ED47CE82B6D431421218773762ED355835E8BE").unwrap(), KeyQuery::FingerprintOrKeygrip(_)));
+        assert_eq!(query("SHA256:abc+/=").unwrap(), KeyQuery::SshSha256("SHA256:abc+/".to_string()));
+        assert_eq!(query("MD5:D2:FA").unwrap(), KeyQuery::SshMd5("MD5:d2:fa".to_string()));
+        assert!(matches!(query("0x7AD47E3"), Err(GpgSshError::InvalidArgument(_))));
+        assert!(matches!(query("alice"), Err(GpgSshError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn lookup_by_any_id() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys_export.txt"))
+            .with_export(include_bytes!("testdata/export.gpg"));
+        let ssh_keys = gpg_keys(&backend, None).unwrap();
+        let lookup = |query: &str| -> Vec<Option<String>> {
+            lookup_keys(&backend, &ssh_keys, &query.parse().unwrap()).unwrap().iter()
+                .map(|key_match| key_match.subkey.map(|subkey| subkey.fingerprint.clone()))
+                .collect()
+        };
+        let ecdsa = || Some("127E07E24618686643B11DFFA8C7C3D3045EC4E1".to_string());
+
+        assert_eq!(lookup("8FEAA54E9F9E62F9"), [None]);
+        assert_eq!(lookup("B208D48E415FF8EAAD4E595B8FEAA54E9F9E62F9"), [None]);
+        assert_eq!(lookup("openpgp:0x045EC4E1"), [ecdsa()]);
+        assert_eq!(lookup("34CAA834EF50872EF972397BF8B922972278C083"), [ecdsa()]);
+        assert_eq!(lookup("SHA256:hvnpM6JNmm2uSuVfuQY7XPshO7XvMepY+yNdqZRA8fE"), [ecdsa()]);
+        assert_eq!(
+            lookup("MD5:a3:d1:e8:1f:3e:97:74:d0:3d:be:a1:3c:0f:11:7f:e3"),
+            [Some("54681D4B9E33325F0825B306605EB74BCC4999EE".to_string())],
+        );
+        // The encryption subkey is found by its id, but has no keygrip or SSH key to match
+        assert_eq!(lookup("CC5603843595DECA"), [Some("EB1966FF8BF2B4A138B547AFCC5603843595DECA".to_string())]);
+        assert!(lookup("24BB02032AD9CEBB7618A46891A61A93ECC5503F").is_empty());
+        assert!(lookup("SHA256:V3A7MNo4FQaolbTZfYU78oN6sJ+fAHb8DW0h1ihZIjA").is_empty());
+    }
+
+    #[test]
+    fn lookup_keys_without_auth_subkey() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys_mixed_secret.txt"));
+        let ssh_keys = all_keys(&backend, None).unwrap();
+        let matches = lookup_keys(&backend, &ssh_keys, &"387E23CF4DAE23F1".parse().unwrap()).unwrap();
+
+        assert_eq!(matches.len(), 1);
+        assert!(matches[0].subkey.is_none());
+        assert!(matches[0].ssh_key.auth_subkeys.is_empty());
+    }
+
+    #[test]
+    fn find_ssh_public_key_by_blob() {
+        let backend = MockBackend::new(include_str!("testdata/list_keys_export.txt"))
+            .with_export(include_bytes!("testdata/export.gpg"));
+        let ssh_keys = gpg_keys(&backend, None).unwrap();
+        let ssh_public_keys = ssh_public_keys(&backend, &ssh_keys).unwrap();
+        let find = |line: &str| {
+            let ssh_public_key = SshPublicKey::parse_authorized_key(line).unwrap();
+            find_ssh_public_key(&ssh_keys, &ssh_public_keys, &ssh_public_key)
+                .map(|(_, auth_subkey)| auth_subkey.openpgp_hex_string().to_string())
+        };
+
+        // The RSA key, with options and another comment
+        let rsa = include_str!("testdata/export_ssh_keys.txt").lines().nth(1).unwrap();
+        let rsa = rsa.rsplit_once(' ').unwrap().0;
+        assert_eq!(find(&format!("no-pty,from=\"10.0.0.1\" {rsa} bob@laptop")).as_deref(), Some("CC4999EE"));
+        assert_eq!(
+            find("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFYmEqP9At0MNxGqeaoiLouSvzmTbE1MRiWLgRQI3RCe openpgp:0x5835E8BE"),
+            None,
+        );
+    }
+
+    #[test]
+    fn find_secret_key_by_id_or_uid() {
+        let backend = MockBackend::new("").with_list_secret_keys_output(include_str!("testdata/list_secret_keys.txt"));
+        let secret_keys = secret_primary_keys(&backend).unwrap();
+        let fingerprint = |query| find_secret_key(&secret_keys, query).map(|key| key.fingerprint.as_str());
+
+        assert_eq!(fingerprint("0E42E055657C72FFB213CF83510DF3503748F565").unwrap(), "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(fingerprint("0x510df3503748f565").unwrap(), "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(fingerprint("3748F565").unwrap(), "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(fingerprint("Alice").unwrap(), "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert!(matches!(fingerprint("7AD47E32"), Err(GpgSshError::KeyNotFound(_))));
+    }
+
+    #[test]
+    fn find_secret_key_like_the_other_queries() {
+        let listing = include_str!("testdata/list_secret_keys.txt").replace("Alice (SSH Key)", "Alice (formerly 4dae23f1)");
+        let backend = MockBackend::new("").with_list_secret_keys_output(&listing);
+        let secret_keys = secret_primary_keys(&backend).unwrap();
+        let fingerprint = |query| find_secret_key(&secret_keys, query).map(|key| key.fingerprint.as_str());
+
+        assert_eq!(fingerprint("0X510DF3503748F565").unwrap(), "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(fingerprint("openpgp:0x3748F565").unwrap(), "0E42E055657C72FFB213CF83510DF3503748F565");
+        // Not ambiguous, the user id of Alice is not searched for a hex id
+        assert_eq!(fingerprint("4DAE23F1").unwrap(), "75D982CDE8656E2D7E7AFCB8387E23CF4DAE23F1");
+    }
+
+    #[test]
+    fn find_by_uppercase_prefix() {
+        let ssh_keys = ssh_keys();
+
+        assert_eq!(find_hex(&ssh_keys, "0X7AD47E32").unwrap(), "7AD47E32");
+        assert_eq!(find_hex(&ssh_keys, "openpgp:0XB5E24591").unwrap(), "B5E24591");
+    }
+
+    #[test]
+    fn find_by_long_and_short_key_id() {
+        let ssh_keys = ssh_keys();
+
+        assert_eq!(find_hex(&ssh_keys, "3A543B3BB5E24591").unwrap(), "B5E24591");
+        assert_eq!(find_hex(&ssh_keys, "0x0c2e24e97ad47e32").unwrap(), "7AD47E32");
+        // The id of the primary key names its only auth subkey
+        assert_eq!(find_hex(&ssh_keys, "2AEDBD63D585271B").unwrap(), "2DDE6F8F");
+        assert_eq!(find_hex(&ssh_keys, "D585271B").unwrap(), "2DDE6F8F");
+    }
+
+    #[test]
+    fn hex_ids_are_not_matched_in_user_ids() {
+        let listing = include_str!("testdata/list_keys_mixed_secret.txt")
+            .replace("Bob (SSH Key)", "Bob (formerly 7ad47e32)");
+        let backend = MockBackend::new(&listing);
+        let ssh_keys = gpg_keys(&backend, None).unwrap();
+
+        assert_eq!(find_hex(&ssh_keys, "7AD47E32").unwrap(), "7AD47E32");
+        assert_eq!(find_hex(&ssh_keys, "formerly").unwrap(), "2DDE6F8F");
+    }
+
+    #[test]
+    fn find_fails_on_no_or_many_matches() {
+        let ssh_keys = ssh_keys();
+
+        assert!(matches!(find_hex(&ssh_keys, "mallory"), Err(GpgSshError::KeyNotFound(_))));
+        assert!(matches!(
+            find_hex(&ssh_keys, "alice"),
+            Err(GpgSshError::AmbiguousKey { candidates, .. }) if candidates.len() == 2
+        ));
+        assert!(matches!(
+            find_hex(&ssh_keys, "0E42E055657C72FFB213CF83510DF3503748F565"),
+            Err(GpgSshError::AmbiguousKey { .. })
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use crate::error::{GpgSshError, Result};
+use super::assuan::{read_response, AgentResponse};
+use super::backend::GpgBackend;
+use super::passphrase::Passphrase;
+
+/// A [`GpgBackend`] replaying canned output instead of running `gpg`, for tests
+///
+/// Every call is recorded, so a test can check what would have been run.
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    list_keys_output: String,
+    list_keys_after_add_key_output: Option<String>,
+    list_secret_keys_output: String,
+    generate_key_output: String,
+    ssh_keys: HashMap<String, String>,
+    export: Vec<u8>,
+    agent_responses: HashMap<String, String>,
+    dirs: HashMap<String, PathBuf>,
+    failing_calls: Vec<String>,
+    calls: RefCell<Vec<String>>,
+    agent_commands: RefCell<Vec<String>>,
+}
+
+impl MockBackend {
+    /// `list_keys_output` is returned by every `list_keys` call, whatever key ids are asked for
+    pub fn new(list_keys_output: &str) -> Self {
+        MockBackend {
+            list_keys_output: list_keys_output.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The colon output `list_keys` returns instead once `quick_add_key` was called, to see the new subkey
+    pub fn with_list_keys_after_add_key_output(mut self, output: &str) -> Self {
+        self.list_keys_after_add_key_output = Some(output.to_string());
+        self
+    }
+
+    /// The colon output returned by every `list_secret_keys` call, by default nothing (no secret keys)
+    pub fn with_list_secret_keys_output(mut self, output: &str) -> Self {
+        self.list_secret_keys_output = output.to_string();
+        self
+    }
+
+    /// The colon output returned by `quick_generate_key`
+    pub fn with_generate_key_output(mut self, output: &str) -> Self {
+        self.generate_key_output = output.to_string();
+        self
+    }
+
+    /// The SSH public key `export_ssh_key` returns for `key_id`
+    pub fn with_ssh_key(mut self, key_id: &str, ssh_key: &str) -> Self {
+        self.ssh_keys.insert(key_id.to_string(), ssh_key.to_string());
+        self
+    }
+
+    /// The OpenPGP packets returned by every `export_keys` call, by default nothing
+    pub fn with_export(mut self, export: &[u8]) -> Self {
+        self.export = export.to_vec();
+        self
+    }
+
+    /// The path `list_dir` returns for `name`
+    pub fn with_dir(mut self, name: &str, path: &str) -> Self {
+        self.dirs.insert(name.to_string(), PathBuf::from(path));
+        self
+    }
+
+    /// The response gpg-agent gives to `command`, as the Assuan lines (e.g. `S KEYINFO ...\nOK\n`
+    /// or `ERR 67108881 Not implemented`). Commands without a response get `OK`
+    pub fn with_agent_response(mut self, command: &str, response: &str) -> Self {
+        self.agent_responses.insert(command.to_string(), response.to_string());
+        self
+    }
+
+    /// Makes `quick_add_key` and `delete_key` fail for the calls containing `call`,
+    /// e.g. `--quick-add-key <fingerprint> ed25519 auth`
+    pub fn with_failing_call(mut self, call: &str) -> Self {
+        self.failing_calls.push(call.to_string());
+        self
+    }
+
+    /// The gpg calls made so far, e.g. `--quick-add-key <fingerprint> ed25519 auth never`
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    /// The commands sent to gpg-agent so far
+    pub fn sent_agent_commands(&self) -> Vec<String> {
+        self.agent_commands.borrow().clone()
+    }
+
+    fn record(&self, args: &[&str]) {
+        self.calls.borrow_mut().push(args.join(" "));
+    }
+
+    /// Records the call and fails it if it was set up to fail
+    fn record_fallible(&self, args: &[&str]) -> Result<()> {
+        self.record(args);
+        let call = args.join(" ");
+        if !self.failing_calls.iter().any(|failing_call| call.contains(failing_call.as_str())) {
+            return Ok(());
+        }
+        Err(GpgSshError::CommandFailed {
+            program: "gpg".to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            stdout: String::new(),
+            stderr: "gpg: failed (mock)".to_string(),
+            exit_code: Some(2),
+        })
+    }
+}
+
+/// The arguments [`SubprocessBackend`](super::SubprocessBackend) adds for the passphrase, which never include it
+fn passphrase_args(passphrase: &Passphrase) -> Vec<&'static str> {
+    match passphrase {
+        Passphrase::Pinentry => Vec::new(),
+        _ => vec!["--batch", "--pinentry-mode", "loopback", "--passphrase-fd", "0"],
+    }
+}
+
+impl GpgBackend for MockBackend {
+    fn list_keys(&self, key_ids: &[String]) -> Result<String> {
+        let mut args = vec!["--list-keys"];
+        args.extend(key_ids.iter().map(String::as_str));
+        self.record(&args);
+        let key_added = self.calls.borrow().iter().any(|call| call.contains("--quick-add-key "));
+        match &self.list_keys_after_add_key_output {
+            Some(output) if key_added => Ok(output.clone()),
+            _ => Ok(self.list_keys_output.clone()),
+        }
+    }
+
+    fn list_secret_keys(&self, key_ids: &[String]) -> Result<String> {
+        let mut args = vec!["--list-secret-keys"];
+        args.extend(key_ids.iter().map(String::as_str));
+        self.record(&args);
+        Ok(self.list_secret_keys_output.clone())
+    }
+
+    fn quick_generate_key(&self, user_id: &str, algo: &str, usage: &str, expire: &str, passphrase: &Passphrase) -> Result<String> {
+        let mut args = passphrase_args(passphrase);
+        args.extend(["--quick-generate-key", user_id, algo, usage, expire]);
+        self.record(&args);
+        Ok(self.generate_key_output.clone())
+    }
+
+    fn quick_add_key(&self, fingerprint: &str, algo: &str, usage: &str, expire: &str, passphrase: &Passphrase) -> Result<()> {
+        let mut args = passphrase_args(passphrase);
+        args.extend(["--quick-add-key", fingerprint, algo, usage, expire]);
+        self.record_fallible(&args)
+    }
+
+    fn delete_key(&self, fingerprint: &str) -> Result<()> {
+        self.record_fallible(&["--batch", "--yes", "--delete-secret-and-public-key", fingerprint])
+    }
+
+    fn export_ssh_key(&self, key_id: &str) -> Result<String> {
+        self.record(&["--export-ssh-key", key_id]);
+        self.ssh_keys.get(key_id).cloned().ok_or_else(|| GpgSshError::CommandFailed {
+            program: "gpg".to_string(),
+            args: vec!["--export-ssh-key".to_string(), key_id.to_string()],
+            stdout: String::new(),
+            stderr: format!("gpg: key \"{key_id}\" not found: No public key"),
+            exit_code: Some(2),
+        })
+    }
+
+    fn export_keys(&self, key_ids: &[String]) -> Result<Vec<u8>> {
+        let mut args = vec!["--export"];
+        args.extend(key_ids.iter().map(String::as_str));
+        self.record(&args);
+        Ok(self.export.clone())
+    }
+
+    fn list_dir(&self, name: &str) -> Result<PathBuf> {
+        self.record(&["--list-dirs", name]);
+        self.dirs.get(name).cloned().ok_or_else(|| GpgSshError::CommandFailed {
+            program: "gpgconf".to_string(),
+            args: vec!["--list-dirs".to_string(), name.to_string()],
+            stdout: String::new(),
+            stderr: format!("gpgconf: unknown directory or file name '{name}'"),
+            exit_code: Some(2),
+        })
+    }
+
+    fn agent_command(&self, command: &str) -> Result<AgentResponse> {
+        self.agent_commands.borrow_mut().push(command.to_string());
+        let response = self.agent_responses.get(command).map_or("OK\n", String::as_str);
+        read_response(&mut response.as_bytes(), &mut std::io::sink(), command, &mut |_| None)
+    }
+}
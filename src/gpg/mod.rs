@@ -1,11 +1,29 @@
 mod helper_fn;
+pub mod colons;
+pub mod assuan;
+mod backend;
+#[cfg(test)]
+mod mock;
 mod create;
+mod expire;
+mod passphrase;
 mod info;
+mod keyinfo;
 mod set;
 mod copy_id;
+mod lookup;
+mod packet;
+mod ssh_agent;
 
-pub(crate) use set::{mark_gpg_key_as_ssh_key, unmark_gpg_key_as_ssh_key};
-pub(crate) use info::{gpg_keys, get_enabled_keygrip, SshKeyInfo};
-#[allow(unused_imports)]
-pub(crate) use create::{create, KeyAlgo};
-pub(crate) use copy_id::{ssh_copy_id, get_public_ssh_keys};
+pub use backend::{GpgBackend, SubprocessBackend};
+#[cfg(test)]
+pub use mock::MockBackend;
+pub use set::{mark_gpg_key_as_ssh_key, unmark_gpg_key_as_ssh_key};
+pub use info::{all_keys, gpg_keys, get_enabled_keygrip, secret_primary_keys, without_public_only, AuthSubkey, SecretAvailability, SecretKey, SshKeyInfo, Subkey};
+pub use keyinfo::{agent_key_infos, key_infos, KeyInfo, KeyInfoFlags, KeyStorage, Protection};
+pub use create::{add_auth, create, CreateOptions, KeyAlgo, SubkeySelection};
+pub use expire::Expire;
+pub use passphrase::Passphrase;
+pub use copy_id::{ssh_copy_id, get_public_ssh_keys, ssh_public_keys};
+pub use lookup::{find_auth_subkey, find_secret_key, find_ssh_public_key, lookup_keys, match_auth_subkeys, KeyMatch, KeyQuery};
+pub use ssh_agent::{is_gpg_agent_ssh_socket, offer_mismatches, OfferMismatch};
@@ -0,0 +1,252 @@
+//! Reads the public keys of a binary `gpg --export` (OpenPGP packets, RFC 4880) and turns
+//! auth subkeys into SSH public keys, the way `gpg --export-ssh-key` does
+
+use sha1::{Digest, Sha1};
+use crate::error::{GpgSshError, Result};
+use crate::ssh::{put_mpint, put_string, SshPublicKey};
+
+const TAG_PUBLIC_KEY: u8 = 6;
+const TAG_PUBLIC_SUBKEY: u8 = 14;
+
+const ALGO_RSA: u8 = 1;
+const ALGO_RSA_SIGN: u8 = 3;
+const ALGO_ECDSA: u8 = 19;
+const ALGO_EDDSA: u8 = 22;
+
+const OID_ED25519: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x0f, 0x01];
+const OID_NISTP256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_NISTP384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const OID_NISTP521: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+
+fn truncated() -> GpgSshError {
+    GpgSshError::Parse("The OpenPGP data of gpg is truncated".to_string())
+}
+
+/// Reads the big endian fields of an OpenPGP packet
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < length {
+            return Err(truncated());
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A multiprecision integer: the length in bits and the big endian bytes
+    fn mpi(&mut self) -> Result<&'a [u8]> {
+        let bits = self.u16()? as usize;
+        self.take(bits.div_ceil(8))
+    }
+
+    /// The header of the next packet, returns the tag and the body
+    fn packet(&mut self) -> Result<(u8, &'a [u8])> {
+        let header = self.u8()?;
+        if header & 0x80 == 0 {
+            return Err(GpgSshError::Parse(format!("Invalid OpenPGP packet header {header:#04x}")));
+        }
+
+        if header & 0x40 == 0 {
+            // Old format: the tag in bits 5-2, the size of the length in bits 1-0
+            let length = match header & 0x03 {
+                0 => self.u8()? as usize,
+                1 => self.u16()? as usize,
+                2 => self.u32()? as usize,
+                _ => self.bytes.len(),
+            };
+            return Ok(((header >> 2) & 0x0f, self.take(length)?));
+        }
+
+        let length = match self.u8()? {
+            length @ 0..=191 => length as usize,
+            first @ 192..=223 => ((first as usize - 192) << 8) + self.u8()? as usize + 192,
+            255 => self.u32()? as usize,
+            _ => return Err(GpgSshError::Parse("Partial body lengths are not expected for keys".to_string())),
+        };
+        Ok((header & 0x3f, self.take(length)?))
+    }
+}
+
+/// The public part of a key, as far as it is needed for SSH
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeyMaterial {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ed25519 { point: Vec<u8> },
+    Ecdsa { curve: &'static str, point: Vec<u8> },
+    /// An algorithm or curve SSH does not know, e.g. ECDH or brainpool
+    Unsupported { algorithm: u8 },
+}
+
+/// A public key or public subkey packet (version 4)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct PublicKey {
+    /// The v4 fingerprint in uppercase hex, the same as gpg lists it
+    pub fingerprint: String,
+    pub is_subkey: bool,
+    material: KeyMaterial,
+}
+
+fn parse_material(algorithm: u8, reader: &mut Reader) -> Result<KeyMaterial> {
+    match algorithm {
+        ALGO_RSA..=ALGO_RSA_SIGN => {
+            let n = reader.mpi()?.to_vec();
+            let e = reader.mpi()?.to_vec();
+            Ok(KeyMaterial::Rsa { n, e })
+        },
+        ALGO_ECDSA | ALGO_EDDSA => {
+            let oid_length = reader.u8()? as usize;
+            let oid = reader.take(oid_length)?;
+            let point = reader.mpi()?;
+            Ok(match (algorithm, oid) {
+                // The 0x40 prefix marks the native point format
+                (ALGO_EDDSA, OID_ED25519) if point.len() == 33 && point[0] == 0x40 => {
+                    KeyMaterial::Ed25519 { point: point[1..].to_vec() }
+                },
+                (ALGO_ECDSA, OID_NISTP256) => KeyMaterial::Ecdsa { curve: "nistp256", point: point.to_vec() },
+                (ALGO_ECDSA, OID_NISTP384) => KeyMaterial::Ecdsa { curve: "nistp384", point: point.to_vec() },
+                (ALGO_ECDSA, OID_NISTP521) => KeyMaterial::Ecdsa { curve: "nistp521", point: point.to_vec() },
+                _ => KeyMaterial::Unsupported { algorithm },
+            })
+        },
+        algorithm => Ok(KeyMaterial::Unsupported { algorithm }),
+    }
+}
+
+/// Parses a public key packet, `None` for versions other than 4
+fn parse_public_key(body: &[u8], is_subkey: bool) -> Result<Option<PublicKey>> {
+    let mut reader = Reader { bytes: body };
+    if reader.u8()? != 4 {
+        return Ok(None);
+    }
+    let _created = reader.u32()?;
+    let algorithm = reader.u8()?;
+    let material = parse_material(algorithm, &mut reader)?;
+
+    // RFC 4880, section 12.2: SHA-1 of 0x99, the two byte length and the packet body
+    let mut hasher = Sha1::new();
+    hasher.update([0x99]);
+    hasher.update((body.len() as u16).to_be_bytes());
+    hasher.update(body);
+    let fingerprint = hasher.finalize().iter().map(|byte| format!("{byte:02X}")).collect();
+
+    Ok(Some(PublicKey { fingerprint, is_subkey, material }))
+}
+
+/// Parses all the public key and subkey packets of `gpg --export`, the other packets are skipped
+pub(super) fn parse_public_keys(bytes: &[u8]) -> Result<Vec<PublicKey>> {
+    let mut reader = Reader { bytes };
+    let mut public_keys = Vec::new();
+    while !reader.bytes.is_empty() {
+        let public_key = match reader.packet()? {
+            (TAG_PUBLIC_KEY, body) => parse_public_key(body, false)?,
+            (TAG_PUBLIC_SUBKEY, body) => parse_public_key(body, true)?,
+            _ => None,
+        };
+        public_keys.extend(public_key);
+    }
+    Ok(public_keys)
+}
+
+impl PublicKey {
+    /// The key in the OpenSSH format with the `openpgp:0x<last 8 hex digits>` comment,
+    /// `None` if SSH does not support the algorithm
+    pub fn ssh_public_key(&self) -> Option<SshPublicKey> {
+        let mut blob = Vec::new();
+        let key_type = match &self.material {
+            KeyMaterial::Rsa { n, e } => {
+                put_string(&mut blob, b"ssh-rsa");
+                put_mpint(&mut blob, e);
+                put_mpint(&mut blob, n);
+                "ssh-rsa".to_string()
+            },
+            KeyMaterial::Ed25519 { point } => {
+                put_string(&mut blob, b"ssh-ed25519");
+                put_string(&mut blob, point);
+                "ssh-ed25519".to_string()
+            },
+            KeyMaterial::Ecdsa { curve, point } => {
+                let key_type = format!("ecdsa-sha2-{curve}");
+                put_string(&mut blob, key_type.as_bytes());
+                put_string(&mut blob, curve.as_bytes());
+                put_string(&mut blob, point);
+                key_type
+            },
+            KeyMaterial::Unsupported { .. } => return None,
+        };
+
+        Some(SshPublicKey {
+            key_type,
+            blob,
+            comment: Some(format!("openpgp:0x{}", &self.fingerprint[self.fingerprint.len() - 8..])),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &[u8] = include_bytes!("testdata/export.gpg");
+
+    #[test]
+    fn parse_fingerprints_like_gpg() {
+        let public_keys = parse_public_keys(EXPORT).unwrap();
+        let fingerprints: Vec<(&str, bool)> = public_keys.iter()
+            .map(|public_key| (public_key.fingerprint.as_str(), public_key.is_subkey))
+            .collect();
+
+        assert_eq!(fingerprints[..3], [
+            ("B208D48E415FF8EAAD4E595B8FEAA54E9F9E62F9", false),
+            ("15ED47CE82B6D431421218773762ED355835E8BE", true),
+            ("54681D4B9E33325F0825B306605EB74BCC4999EE", true),
+        ]);
+        assert_eq!(public_keys.len(), 7);
+    }
+
+    #[test]
+    fn ssh_public_keys_match_gpg_export_ssh_key() {
+        // `gpg --export-ssh-key <fingerprint>!` of each auth subkey of the export
+        let expected: Vec<&str> = include_str!("testdata/export_ssh_keys.txt").lines().collect();
+
+        let ssh_public_keys: Vec<String> = parse_public_keys(EXPORT).unwrap().iter()
+            .filter(|public_key| public_key.is_subkey)
+            .filter_map(PublicKey::ssh_public_key)
+            .map(|ssh_public_key| ssh_public_key.to_string())
+            .collect();
+
+        assert_eq!(ssh_public_keys, expected);
+    }
+
+    #[test]
+    fn unsupported_algorithms_have_no_ssh_key() {
+        let cv25519 = parse_public_keys(EXPORT).unwrap().into_iter()
+            .find(|public_key| public_key.fingerprint == "EB1966FF8BF2B4A138B547AFCC5603843595DECA")
+            .unwrap();
+
+        assert_eq!(cv25519.ssh_public_key(), None);
+    }
+
+    #[test]
+    fn truncated_data_is_an_error() {
+        assert!(matches!(parse_public_keys(&EXPORT[..EXPORT.len() - 1]), Err(GpgSshError::Parse(_))));
+        assert!(matches!(parse_public_keys(&[0x00]), Err(GpgSshError::Parse(_))));
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+use crate::error::{GpgSshError, Result};
+
+/// How gpg gets the passphrase protecting the keys it creates
+///
+/// Unless it is [`Passphrase::Pinentry`], gpg runs with `--batch --pinentry-mode loopback --passphrase-fd 0`
+/// and reads the passphrase from stdin, so it never shows up in the arguments of gpg.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub enum Passphrase {
+    /// gpg-agent asks for the passphrase with pinentry
+    #[default]
+    Pinentry,
+    /// The keys are not protected by a passphrase
+    NoProtection,
+    /// The passphrase is passed on to gpg
+    Secret(String),
+}
+
+impl Debug for Passphrase {
+    /// Never shows the passphrase itself, e.g. when the options are logged
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Passphrase::Pinentry => write!(f, "Pinentry"),
+            Passphrase::NoProtection => write!(f, "NoProtection"),
+            Passphrase::Secret(_) => write!(f, "Secret(..)"),
+        }
+    }
+}
+
+impl Passphrase {
+    /// Takes the first line of `content` as the passphrase, like gpg does with `--passphrase-fd`
+    fn from_content(content: &str, source: &str) -> Result<Self> {
+        let passphrase = content.lines().next().unwrap_or_default();
+        if passphrase.is_empty() {
+            return Err(GpgSshError::InvalidArgument(format!(
+                "The passphrase from {source} is empty, use --no-protection to create keys without a passphrase"
+            )));
+        }
+        Ok(Passphrase::Secret(passphrase.to_string()))
+    }
+
+    /// Reads the passphrase from the first line of the file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::from_content(&content, &path.display().to_string())
+    }
+
+    /// Reads the passphrase from the first line of an open file descriptor, e.g. `0` for stdin
+    /// or `3` with `3< passphrase.txt`
+    pub fn from_fd(fd: u32) -> Result<Self> {
+        let content = std::fs::read_to_string(format!("/dev/fd/{fd}"))?;
+        Self::from_content(&content, &format!("file descriptor {fd}"))
+    }
+
+    /// Reads the passphrase from the environment variable
+    pub fn from_env(name: &str) -> Result<Self> {
+        let content = std::env::var(name).map_err(|err| GpgSshError::InvalidArgument(
+            format!("Could not read the passphrase from ${name}: {err}")
+        ))?;
+        Self::from_content(&content, &format!("${name}"))
+    }
+
+    /// What gpg reads from stdin with `--passphrase-fd 0`, `None` if pinentry should ask
+    pub(super) fn stdin(&self) -> Option<Vec<u8>> {
+        match self {
+            Passphrase::Pinentry => None,
+            Passphrase::NoProtection => Some(b"\n".to_vec()),
+            Passphrase::Secret(passphrase) => Some(format!("{passphrase}\n").into_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+
+    #[test]
+    fn first_line_is_the_passphrase() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "correct horse battery staple\nignored").unwrap();
+
+        let passphrase = Passphrase::from_file(file.path()).unwrap();
+        assert_eq!(passphrase, Passphrase::Secret("correct horse battery staple".to_string()));
+        assert_eq!(passphrase.stdin().unwrap(), b"correct horse battery staple\n");
+    }
+
+    #[test]
+    fn empty_passphrase_is_refused() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(matches!(Passphrase::from_file(file.path()), Err(GpgSshError::InvalidArgument(_))));
+        assert!(matches!(Passphrase::from_content("\nsecret", "test"), Err(GpgSshError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn stdin_per_mode() {
+        assert_eq!(Passphrase::Pinentry.stdin(), None);
+        assert_eq!(Passphrase::NoProtection.stdin().unwrap(), b"\n");
+    }
+
+    #[test]
+    fn debug_hides_the_secret() {
+        assert_eq!(format!("{:?}", Passphrase::Secret("hunter2".to_string())), "Secret(..)");
+    }
+}
@@ -1,43 +1,69 @@
-use std::process::{Command, Stdio};
 use log::debug;
-use crate::gpg::helper_fn::cmd_error_handler;
+use crate::error::Result;
+use crate::gpg::backend::GpgBackend;
 
-pub(crate) fn mark_gpg_key_as_ssh_key<S: AsRef<str> + std::fmt::Debug>(
-    list_of_keygrip: &Vec<S>,
-) {
-    if list_of_keygrip.len() == 0 { return; }
-
-    let mut command = Command::new("gpg-connect-agent");
-    command.stdin(Stdio::null());
+/// Sets the `Use-for-ssh` attribute on the keygrips, so gpg-agent offers them to the SSH client
+pub fn mark_gpg_key_as_ssh_key<S: AsRef<str> + std::fmt::Debug>(
+    backend: &dyn GpgBackend,
+    list_of_keygrip: &[S],
+) -> Result<()> {
+    if list_of_keygrip.is_empty() { return Ok(()); }
 
     for keygrip in list_of_keygrip {
-        command.arg(format!("KEYATTR {} Use-for-ssh: true", keygrip.as_ref()));
+        backend.agent_command(&format!("KEYATTR {} Use-for-ssh: true", keygrip.as_ref()))?;
     }
-
-    let output = command
-        .output()
-        .expect("Failed to execute command");
-
-    cmd_error_handler(&command, &output);
     debug!("Added the keygrip: {:?}", list_of_keygrip);
+    Ok(())
 }
 
-pub(crate) fn unmark_gpg_key_as_ssh_key<S: AsRef<str> + std::fmt::Debug>(
-    list_of_keygrip: &Vec<S>,
-) {
-    if list_of_keygrip.len() == 0 { return; }
-    
-    let mut command = Command::new("gpg-connect-agent");
-    command.stdin(Stdio::null());
+/// Clears the `Use-for-ssh` attribute on the keygrips, so gpg-agent no longer offers them to the SSH client
+pub fn unmark_gpg_key_as_ssh_key<S: AsRef<str> + std::fmt::Debug>(
+    backend: &dyn GpgBackend,
+    list_of_keygrip: &[S],
+) -> Result<()> {
+    if list_of_keygrip.is_empty() { return Ok(()); }
 
     for keygrip in list_of_keygrip {
-        command.arg(format!("KEYATTR {} Use-for-ssh: false", keygrip.as_ref()));
+        backend.agent_command(&format!("KEYATTR {} Use-for-ssh: false", keygrip.as_ref()))?;
     }
+    debug!("Remove the keygrip: {:?}", list_of_keygrip);
+    Ok(())
+}
 
-    let output = command
-        .output()
-        .expect("Failed to execute command");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GpgSshError;
+    use crate::gpg::MockBackend;
 
-    cmd_error_handler(&command, &output);
-    debug!("Remove the keygrip: {:?}", list_of_keygrip);
+    #[test]
+    fn mark_and_unmark_send_keyattr() {
+        let backend = MockBackend::new("");
+        mark_gpg_key_as_ssh_key(&backend, &["AAAA", "BBBB"]).unwrap();
+        unmark_gpg_key_as_ssh_key(&backend, &["CCCC"]).unwrap();
+
+        assert_eq!(backend.sent_agent_commands(), vec![
+            "KEYATTR AAAA Use-for-ssh: true",
+            "KEYATTR BBBB Use-for-ssh: true",
+            "KEYATTR CCCC Use-for-ssh: false",
+        ]);
+    }
+
+    #[test]
+    fn agent_errors_are_returned() {
+        let backend = MockBackend::new("")
+            .with_agent_response("KEYATTR AAAA Use-for-ssh: true", "ERR 67108881 Not implemented <GPG Agent>\n");
+
+        let result = mark_gpg_key_as_ssh_key(&backend, &["AAAA", "BBBB"]);
+        assert!(matches!(result, Err(GpgSshError::Agent { code: 67108881, .. })));
+        assert_eq!(backend.sent_agent_commands(), vec!["KEYATTR AAAA Use-for-ssh: true"]);
+    }
+
+    #[test]
+    fn nothing_is_sent_without_keygrips() {
+        let backend = MockBackend::new("");
+        mark_gpg_key_as_ssh_key::<&str>(&backend, &[]).unwrap();
+
+        assert!(backend.sent_agent_commands().is_empty());
+    }
 }
@@ -0,0 +1,120 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use log::debug;
+use crate::error::Result;
+use crate::ssh::Identity;
+use super::backend::GpgBackend;
+use super::copy_id::ssh_public_keys;
+use super::info::{get_enabled_keygrip, SecretAvailability, SshKeyInfo};
+
+/// Whether `ssh_auth_sock` is the SSH socket of gpg-agent (`gpgconf --list-dirs agent-ssh-socket`)
+///
+/// If it is not, ssh talks to another agent, which knows nothing of the keys enabled in gpg-agent.
+pub fn is_gpg_agent_ssh_socket(backend: &dyn GpgBackend, ssh_auth_sock: &Path) -> Result<bool> {
+    let agent_ssh_socket = backend.list_dir("agent-ssh-socket")?;
+
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    Ok(canonical(ssh_auth_sock) == canonical(&agent_ssh_socket))
+}
+
+/// An auth subkey which the SSH agent offers although it is disabled in gpg-agent, or the other way round
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OfferMismatch {
+    /// Enabled in gpg-agent, but not offered to ssh
+    NotOffered(String),
+    /// Not enabled in gpg-agent, but still offered to ssh (e.g. because it is listed in `sshcontrol`)
+    StillOffered(String),
+}
+
+impl Display for OfferMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OfferMismatch::NotOffered(key) => write!(f, "The key {key} is enabled, but the SSH agent does not offer it"),
+            OfferMismatch::StillOffered(key) => write!(
+                f, "The key {key} is not enabled, but the SSH agent still offers it (is it listed in sshcontrol?)"
+            ),
+        }
+    }
+}
+
+/// Compares the keys the SSH agent offers (see [`SshAgentClient::request_identities`](crate::ssh::SshAgentClient::request_identities))
+/// with the auth subkeys enabled in gpg-agent
+///
+/// Auth subkeys without a secret key are skipped, as no agent can offer them.
+pub fn offer_mismatches(
+    backend: &dyn GpgBackend,
+    ssh_keys: &[SshKeyInfo],
+    identities: &[Identity],
+) -> Result<Vec<OfferMismatch>> {
+    let enabled_keygrip = get_enabled_keygrip(backend)?;
+    let ssh_public_keys = ssh_public_keys(backend, ssh_keys)?;
+
+    let mut mismatches = Vec::new();
+    for ssh_key in ssh_keys {
+        for auth_subkey in &ssh_key.auth_subkeys {
+            if auth_subkey.secret == SecretAvailability::PublicOnly { continue }
+
+            let ssh_public_key = match ssh_public_keys.get(&auth_subkey.keygrip) {
+                Some(Ok(ssh_public_key)) => ssh_public_key,
+                Some(Err(err)) => {
+                    debug!("Could not export the SSH public key of {}: {err}", auth_subkey.fingerprint);
+                    continue
+                },
+                None => continue,
+            };
+            let offered = identities.iter().any(|identity| identity.blob == ssh_public_key.blob);
+            let enabled = enabled_keygrip.contains(&auth_subkey.keygrip);
+
+            if enabled && !offered {
+                mismatches.push(OfferMismatch::NotOffered(ssh_key.describe(auth_subkey)));
+            } else if !enabled && offered {
+                mismatches.push(OfferMismatch::StillOffered(ssh_key.describe(auth_subkey)));
+            }
+        }
+    }
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpg::{gpg_keys, MockBackend};
+    use crate::ssh::SshPublicKey;
+
+    const ALICE_B5E24591: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA/bBSgH9m/oljRQgtGeO10dNLFeqkqs1sHwxMi/bPFY openpgp:0xB5E24591";
+    const ALICE_7AD47E32: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFYmEqP9At0MNxGqeaoiLouSvzmTbE1MRiWLgRQI3RCe openpgp:0x7AD47E32";
+
+    fn identity(line: &str) -> Identity {
+        Identity { blob: SshPublicKey::parse(line).unwrap().blob, comment: String::new() }
+    }
+
+    #[test]
+    fn reports_keys_offered_differently_than_enabled() {
+        let secret_keys = include_str!("testdata/list_secret_keys.txt")
+            .replace("ssb:u:255:22:0C2E24E97AD47E32:1792314498::::::a:::#:", "ssb:u:255:22:0C2E24E97AD47E32:1792314498::::::a:::+:");
+        let backend = MockBackend::new(include_str!("testdata/list_keys_two_auth.txt"))
+            .with_list_secret_keys_output(&secret_keys)
+            .with_ssh_key("2D7E8C21F36DC2BC308110C43A543B3BB5E24591!", ALICE_B5E24591)
+            .with_ssh_key("5BCC596B65ED955F64C31BBC0C2E24E97AD47E32!", ALICE_7AD47E32)
+            .with_agent_response(
+                "KEYINFO --list --need-attr=Use-for-ssh",
+                "S KEYINFO 74E85BD09EB34D98EE2AC0F717B384B8E28DF93F D - - - P - - -\nOK\n",
+            );
+        let ssh_keys = gpg_keys(&backend, None).unwrap();
+
+        assert_eq!(offer_mismatches(&backend, &ssh_keys, &[identity(ALICE_B5E24591)]).unwrap(), vec![]);
+        assert_eq!(offer_mismatches(&backend, &ssh_keys, &[identity(ALICE_7AD47E32)]).unwrap(), vec![
+            OfferMismatch::StillOffered("Alice (SSH Key) <alice@example.com> openpgp:0x7AD47E32".to_string()),
+            OfferMismatch::NotOffered("Alice (SSH Key) <alice@example.com> openpgp:0xB5E24591".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn compares_the_ssh_socket_with_gpgconf() {
+        let backend = MockBackend::new("").with_dir("agent-ssh-socket", "/run/user/1000/gnupg/S.gpg-agent.ssh");
+
+        assert!(is_gpg_agent_ssh_socket(&backend, Path::new("/run/user/1000/gnupg/S.gpg-agent.ssh")).unwrap());
+        assert!(!is_gpg_agent_ssh_socket(&backend, Path::new("/tmp/ssh-XXXX/agent.1234")).unwrap());
+    }
+}
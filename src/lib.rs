@@ -0,0 +1,20 @@
+//! A library to help managing SSH keys using GnuPG keystore
+//!
+//! The GPG keys with an auth subkey are listed with [`gpg::gpg_keys`],
+//! gpg-agent is told to offer them to the SSH client with [`gpg::mark_gpg_key_as_ssh_key`]
+//! (and not to with [`gpg::unmark_gpg_key_as_ssh_key`]),
+//! their public SSH keys are exported with [`gpg::get_public_ssh_keys`]
+//! and new keys are generated with [`gpg::create`].
+//!
+//! All of them talk to GnuPG through a [`gpg::GpgBackend`]. [`gpg::SubprocessBackend`] runs
+//! `gpg` and talks to gpg-agent over its socket (see [`gpg::assuan`]), while the `MockBackend` of the tests replays canned output.
+//!
+//! The interactive pickers (skim) in [`fuzzy`] are behind the `fuzzy` feature, which is enabled by default.
+
+pub mod error;
+pub mod gpg;
+pub mod ssh;
+#[cfg(feature = "fuzzy")]
+pub mod fuzzy;
+
+pub use error::{GpgSshError, Result};
@@ -1,14 +1,18 @@
-extern crate skim;
-extern crate core;
-
 mod arguments;
-mod gpg;
-mod fuzzy;
+mod output;
 
-use arguments::{get_args, SubCommands};
-use log::{debug};
-use gpg::{create, gpg_keys, ssh_copy_id, unmark_gpg_key_as_ssh_key, get_public_ssh_keys};
-use fuzzy::{fzf_set, fzf_copy_id};
+use std::collections::HashSet;
+use std::io::{stdin, Read};
+use std::path::Path;
+use std::process::exit;
+use arguments::{get_args, KeyFormat, MainArgs, OutputFormat, SubCommands, SubkeyArg, ToggleArgs};
+use output::{print_json, write_json, AuthorizedKeyOutput, AuthorizedKeyStatus};
+use log::{debug, error, info, warn};
+use gpg_ssh::{GpgSshError, Result};
+use gpg_ssh::gpg::{add_auth, all_keys, find_ssh_public_key, lookup_keys, ssh_public_keys, KeyMatch, create, find_secret_key, secret_primary_keys, CreateOptions, Passphrase, SubkeySelection, gpg_keys, ssh_copy_id, mark_gpg_key_as_ssh_key, unmark_gpg_key_as_ssh_key, get_public_ssh_keys, get_enabled_keygrip, find_auth_subkey, agent_key_infos, without_public_only, is_gpg_agent_ssh_socket, offer_mismatches, AuthSubkey, GpgBackend, KeyInfo, KeyStorage, SecretAvailability, SshKeyInfo, SubprocessBackend};
+use gpg_ssh::gpg::colons::format_date;
+use gpg_ssh::ssh::{SshAgentClient, SshPublicKey};
+use gpg_ssh::fuzzy::{fzf_set, fzf_copy_id, fzf_secret_key};
 
 fn main() {
     let args = get_args();
@@ -19,67 +23,396 @@ fn main() {
         .init();
     debug!("{:?}", args);
 
+    if let Err(err) = run(args) {
+        error!("{err}");
+        exit(err.exit_code());
+    }
+}
+
+/// The state of the auth subkey worth knowing about, e.g. ` [public only, expires 2027-10-18]`
+///
+/// `key_info` is what gpg-agent knows about the key, if anything.
+fn key_tags(ssh_key: &SshKeyInfo, auth_subkey: &AuthSubkey, key_info: Option<&KeyInfo>) -> String {
+    let mut tags = Vec::new();
+
+    if auth_subkey.secret != SecretAvailability::Local {
+        tags.push(auth_subkey.secret.to_string());
+    }
+    if let Some(key_info) = key_info {
+        let on_card = matches!(auth_subkey.secret, SecretAvailability::Card { .. });
+        if key_info.storage == KeyStorage::Smartcard && !on_card {
+            tags.push(format!("on card {}", key_info.serial_number.as_deref().unwrap_or("?")));
+        }
+        if key_info.cached {
+            tags.push("passphrase cached".to_string());
+        }
+    }
+    if let Some(reason) = ssh_key.unusable_reason(auth_subkey) {
+        tags.push(reason.to_string());
+    }
+    if ssh_key.is_disabled() {
+        tags.push("disabled".to_string());
+    }
+    if let Some(expires) = auth_subkey.expires.into_iter().chain(ssh_key.main_expires).min() {
+        tags.push(format!("expires {}", format_date(expires)));
+    }
+
+    if tags.is_empty() { String::new() } else { format!(" [{}]", tags.join(", ")) }
+}
+
+/// Keeps only the auth subkeys matching the predicate, and the keys with at least one of them left
+fn retain_auth_subkeys(ssh_keys: Vec<SshKeyInfo>, predicate: impl Fn(&AuthSubkey) -> bool) -> Vec<SshKeyInfo> {
+    ssh_keys.into_iter().filter_map(|mut ssh_key| {
+        ssh_key.auth_subkeys.retain(&predicate);
+        Some(ssh_key).filter(|ssh_key| !ssh_key.auth_subkeys.is_empty())
+    }).collect()
+}
+
+/// Enables and disables the keys given by `--enable`, `--disable` and `--only`
+fn toggle_keys(backend: &dyn GpgBackend, ssh_keys: &[SshKeyInfo], args: &ToggleArgs) -> Result<()> {
+    let enabled_keygrip = get_enabled_keygrip(backend)?;
+
+    let mut enable = Vec::new();
+    for query in args.enable.iter().chain(&args.only) {
+        let (ssh_key, auth_subkey) = find_auth_subkey(ssh_keys, query)?;
+        if !enabled_keygrip.contains(&auth_subkey.keygrip) {
+            ssh_key.ensure_usable(auth_subkey, args.force)?;
+            enable.push(auth_subkey.keygrip.as_str());
+        }
+    }
+
+    let mut disable = Vec::new();
+    for query in &args.disable {
+        let (_, auth_subkey) = find_auth_subkey(ssh_keys, query)?;
+        disable.push(auth_subkey.keygrip.as_str());
+    }
+
+    if !args.only.is_empty() {
+        let only: HashSet<&str> = args.only.iter()
+            .map(|query| find_auth_subkey(ssh_keys, query).map(|(_, auth_subkey)| auth_subkey.keygrip.as_str()))
+            .collect::<Result<_>>()?;
+        disable.extend(enabled_keygrip.iter().map(String::as_str).filter(|keygrip| !only.contains(keygrip)));
+    }
+
+    mark_gpg_key_as_ssh_key(backend, &enable)?;
+    unmark_gpg_key_as_ssh_key(backend, &disable)
+}
+
+/// Warns if ssh does not see the keys the way they are enabled in gpg-agent, e.g. right after toggling them
+fn check_ssh_agent(backend: &dyn GpgBackend, ssh_auth_sock: &Path, ssh_keys: &[SshKeyInfo]) {
+    match is_gpg_agent_ssh_socket(backend, ssh_auth_sock) {
+        Ok(true) => {},
+        Ok(false) => {
+            warn!(
+                "SSH_AUTH_SOCK ({}) is not the SSH socket of gpg-agent, so ssh does not see the keys enabled here",
+                ssh_auth_sock.display(),
+            );
+            return
+        },
+        Err(err) => debug!("Could not find the SSH socket of gpg-agent: {err}"),
+    }
+
+    let mismatches = SshAgentClient::connect(ssh_auth_sock)
+        .and_then(|mut client| client.request_identities())
+        .and_then(|identities| offer_mismatches(backend, ssh_keys, &identities));
+    match mismatches {
+        Ok(mismatches) => mismatches.iter().for_each(|mismatch| warn!("{mismatch}")),
+        Err(err) => warn!("Could not check which keys the SSH agent offers: {err}"),
+    }
+}
+
+/// Encodes a public SSH key in the OpenSSH format (a line of `gpg --export-ssh-key`) for `public-key --format`
+fn format_public_key(key: &str, format: KeyFormat, host: Option<&str>) -> Result<String> {
+    let ssh_public_key = SshPublicKey::parse(key)?;
+    Ok(match format {
+        KeyFormat::Openssh => key.to_string(),
+        KeyFormat::Rfc4716 => ssh_public_key.to_rfc4716().trim_end().to_string(),
+        KeyFormat::Pem => ssh_public_key.to_pem()?.trim_end().to_string(),
+        KeyFormat::Jwk => ssh_public_key.to_jwk()?,
+        // clap requires --host for this format
+        KeyFormat::KnownHosts => ssh_public_key.to_known_hosts(host.unwrap_or("*")),
+    })
+}
+
+/// Prints a key found by `lookup`: the primary key, its user id and the subkey the id names
+fn print_key_match(key_match: &KeyMatch) {
+    println!("Key: {}\nName: {}", key_match.ssh_key.main_key_id, key_match.ssh_key.main_name);
+    match key_match.subkey {
+        None => println!("Subkey: none, the id names the primary key"),
+        Some(subkey) => {
+            println!("Subkey: {} ({}, usage {})", subkey.fingerprint, subkey.algorithm, subkey.capabilities);
+            if let Some(auth_subkey) = key_match.auth_subkey() {
+                println!("Keygrip: {}\nSSH key: openpgp:0x{}", auth_subkey.keygrip, auth_subkey.openpgp_hex_string());
+            }
+        },
+    }
+    if key_match.ssh_key.auth_subkeys.is_empty() {
+        println!("SSH key: none, the key has no auth subkey");
+    }
+}
+
+/// Reports which auth subkey each SSH public key of the file (`-` for stdin) is, for
+/// `lookup --authorized-keys` and `lookup --pubkey`
+fn lookup_ssh_public_keys(backend: &dyn GpgBackend, ssh_keys: &[SshKeyInfo], path: &Path, output: OutputFormat) -> Result<()> {
+    let content = if path == Path::new("-") {
+        let mut content = String::new();
+        stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        std::fs::read_to_string(path)?
+    };
+    let ssh_public_keys = ssh_public_keys(backend, ssh_keys)?;
+
+    // A key not found may still be one of the auth subkeys gpg could not export, so it is not reported as unknown
+    let not_exported: Vec<String> = ssh_keys.iter().flat_map(|ssh_key| {
+        ssh_key.auth_subkeys.iter().map(move |auth_subkey| (ssh_key, auth_subkey))
+    }).filter_map(|(ssh_key, auth_subkey)| {
+        let Some(Err(err)) = ssh_public_keys.get(&auth_subkey.keygrip) else { return None };
+        warn!("Could not export the SSH public key of {}: {err}", ssh_key.describe(auth_subkey));
+        Some(ssh_key.describe(auth_subkey))
+    }).collect();
+
+    let mut lines = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue }
+        lines.push(match SshPublicKey::parse_authorized_key(line) {
+            Ok(ssh_public_key) => Ok((index + 1, ssh_public_key)),
+            Err(err) => Err((index + 1, format!("{}:{}: {err}", path.display(), index + 1))),
+        });
+    }
+
+    let report: Vec<AuthorizedKeyOutput> = lines.iter().map(|line| match line {
+        Ok((line, ssh_public_key)) => {
+            let found = find_ssh_public_key(ssh_keys, &ssh_public_keys, ssh_public_key);
+            let (status, error) = match found {
+                Some(_) => (AuthorizedKeyStatus::Known, None),
+                None if not_exported.is_empty() => (AuthorizedKeyStatus::Unknown, None),
+                None => (
+                    AuthorizedKeyStatus::Unverified,
+                    Some(format!("gpg could not export {} to compare with", not_exported.join(", "))),
+                ),
+            };
+            AuthorizedKeyOutput {
+                line: *line,
+                status,
+                error,
+                key_type: Some(&ssh_public_key.key_type),
+                ssh_fingerprint: Some(ssh_public_key.fingerprint_sha256()),
+                comment: ssh_public_key.comment.as_deref(),
+                main_key_id: found.map(|(ssh_key, _)| ssh_key.main_key_id.as_str()),
+                main_name: found.map(|(ssh_key, _)| ssh_key.main_name.as_str()),
+                auth_subkey: found.map(|(_, auth_subkey)| auth_subkey),
+            }
+        }
+        Err((line, err)) => AuthorizedKeyOutput {
+            line: *line,
+            status: AuthorizedKeyStatus::Unparseable,
+            error: Some(err.clone()),
+            key_type: None,
+            ssh_fingerprint: None,
+            comment: None,
+            main_key_id: None,
+            main_name: None,
+            auth_subkey: None,
+        },
+    }).collect();
+
+    if output != OutputFormat::Table {
+        return write_json(&report, output)
+    }
+
+    for key in &report {
+        let ssh_fingerprint = key.ssh_fingerprint.as_deref().unwrap_or_default();
+        match (key.status, key.main_name, key.auth_subkey) {
+            (AuthorizedKeyStatus::Known, Some(main_name), Some(auth_subkey)) => println!(
+                "{}: known       {ssh_fingerprint} {main_name} openpgp:0x{} ({})",
+                key.line, auth_subkey.openpgp_hex_string(), auth_subkey.fingerprint,
+            ),
+            (AuthorizedKeyStatus::Unparseable, _, _) => println!(
+                "{}: unparseable ({})", key.line, key.error.as_deref().unwrap_or_default(),
+            ),
+            (AuthorizedKeyStatus::Unverified, _, _) => println!(
+                "{}: unverified  {ssh_fingerprint} {} ({})",
+                key.line, key.comment.unwrap_or_default(), key.error.as_deref().unwrap_or_default(),
+            ),
+            _ => println!("{}: unknown     {ssh_fingerprint} {}", key.line, key.comment.unwrap_or_default()),
+        }
+    }
+    Ok(())
+}
+
+fn run(args: MainArgs) -> Result<()> {
+    let backend = SubprocessBackend::new();
+    let ssh_auth_sock = args.ssh_auth_sock;
+
     match args.sub_commands {
-        SubCommands::Create(args) => create(
-            args.name,
-            args.email,
-            None,
-            None,
-        ),
+        SubCommands::Create(args) => {
+            let passphrase = if let Some(path) = &args.passphrase_file {
+                Passphrase::from_file(path)?
+            } else if let Some(fd) = args.passphrase_fd {
+                Passphrase::from_fd(fd)?
+            } else if let Some(name) = &args.passphrase_env {
+                Passphrase::from_env(name)?
+            } else if args.no_protection {
+                Passphrase::NoProtection
+            } else {
+                Passphrase::Pinentry
+            };
+            let options = CreateOptions {
+                comment: args.comment,
+                key_algo: args.algo,
+                expire: args.expire,
+                sign_expire: args.sign_expire.unwrap_or(args.expire),
+                encr_expire: args.encr_expire.unwrap_or(args.expire),
+                auth_expire: args.auth_expire.unwrap_or(args.expire),
+                subkeys: SubkeySelection {
+                    sign: args.subkeys.contains(&SubkeyArg::Sign),
+                    encr: args.subkeys.contains(&SubkeyArg::Encr) && !args.no_encrypt,
+                    auth: args.subkeys.contains(&SubkeyArg::Auth),
+                },
+                keep_partial: args.keep_partial,
+                passphrase,
+            };
+            let fingerprint = create(&backend, args.name, args.email, &options)?;
+            if options.subkeys.auth {
+                check_ssh_agent(&backend, &ssh_auth_sock, &gpg_keys(&backend, Some(vec![fingerprint]))?);
+            }
+        },
+        SubCommands::AddAuth(args) => {
+            let fingerprint = match args.key {
+                Some(query) => find_secret_key(&secret_primary_keys(&backend)?, &query)?.fingerprint.clone(),
+                None => match fzf_secret_key(&backend)? {
+                    Some(fingerprint) => fingerprint,
+                    None => return Ok(()),
+                },
+            };
+            let auth_subkey = add_auth(&backend, &fingerprint, args.algo, args.expire)?;
+            info!("Added the auth subkey {} to {fingerprint}", auth_subkey.fingerprint);
+            check_ssh_agent(&backend, &ssh_auth_sock, &gpg_keys(&backend, Some(vec![fingerprint]))?);
+        },
         SubCommands::List(args) => {
-            let ssh_keys = gpg_keys(None);
+            let mut ssh_keys = gpg_keys(&backend, None)?;
+            if !args.include_public_only { ssh_keys = without_public_only(ssh_keys) }
+
+            if args.output != OutputFormat::Table {
+                return print_json(&backend, &ssh_keys, args.output)
+            }
+
+            let key_infos = agent_key_infos(&backend);
             for ssh_key in ssh_keys {
-                if args.include_key_id {
-                    println!("{} {}", ssh_key.main_key_id, ssh_key.main_name)
-                } else {
-                    println!("{}", ssh_key.main_name)
+                for auth_subkey in &ssh_key.auth_subkeys {
+                    let tags = key_tags(&ssh_key, auth_subkey, key_infos.get(&auth_subkey.keygrip));
+                    if args.include_key_id {
+                        println!("{} {} openpgp:0x{}{tags}",
+                                 ssh_key.main_key_id, ssh_key.main_name, auth_subkey.openpgp_hex_string())
+                    } else {
+                        println!("{} openpgp:0x{}{tags}", ssh_key.main_name, auth_subkey.openpgp_hex_string())
+                    }
                 }
             }
         },
         SubCommands::Toggle(args) => {
-            let ssh_keys = gpg_keys(None);
+            let mut ssh_keys = gpg_keys(&backend, None)?;
+            if !args.include_public_only { ssh_keys = without_public_only(ssh_keys) }
+
             if args.disable_all {
-                let list_of_keygrip = ssh_keys.iter().map(|info|{
-                    info.auth_keygrip.as_str()
+                let list_of_keygrip: Vec<&str> = ssh_keys.iter().flat_map(|info|{
+                    info.auth_subkeys.iter().map(|auth_subkey| auth_subkey.keygrip.as_str())
                 }).collect();
-                unmark_gpg_key_as_ssh_key(&list_of_keygrip);
+                unmark_gpg_key_as_ssh_key(&backend, &list_of_keygrip)?;
+            } else if !args.enable.is_empty() || !args.disable.is_empty() || !args.only.is_empty() {
+                toggle_keys(&backend, &ssh_keys, &args)?
             } else {
-                fzf_set(ssh_keys)
+                fzf_set(&backend, ssh_keys.clone(), args.force)?
             }
+            check_ssh_agent(&backend, &ssh_auth_sock, &ssh_keys);
         },
         SubCommands::CopyId(args) => {
-            let keys = fzf_copy_id();
-            if keys.len() >= 1 {
-                ssh_copy_id(args.args, keys, args.keep_temp_files).expect("Unknown error")
+            let keys = fzf_copy_id(&backend, args.force)?;
+            if !keys.is_empty() {
+                ssh_copy_id(&backend, args.args, keys, args.keep_temp_files)?
             }
         },
         SubCommands::PublicKey(args) => {
             if let Some(openpgp_hex_string) = args.lookup_hex {
-                let ssh_keys = gpg_keys(None);
-                let mut is_match = false;
-                for ssh_key_info in ssh_keys {
-                    if openpgp_hex_string == ssh_key_info.auth_openpgp_hex_string() {
+                let ssh_keys = retain_auth_subkeys(gpg_keys(&backend, None)?, |auth_subkey| {
+                    openpgp_hex_string == auth_subkey.openpgp_hex_string()
+                });
+
+                if args.output != OutputFormat::Table {
+                    return print_json(&backend, &ssh_keys, args.output)
+                }
+
+                for ssh_key_info in &ssh_keys {
+                    for auth_subkey in &ssh_key_info.auth_subkeys {
                         println!(
                             "Key: {}\nName: {}\n\
                             Fingerprint (Auth Subkey): {}\nKeygrip (Auth Subkey): {}",
                             ssh_key_info.main_key_id,
                             ssh_key_info.main_name,
-                            ssh_key_info.auth_fingerprint,
-                            ssh_key_info.auth_keygrip,
+                            auth_subkey.fingerprint,
+                            auth_subkey.keygrip,
                         );
-                        is_match = true;
-                        break
                     }
                 }
-                if ! is_match {
+                if ssh_keys.is_empty() {
                     println!("Did not find a match for the hex string: 0x{openpgp_hex_string}")
                 }
             } else {
-                let keys = fzf_copy_id();
-                for key in get_public_ssh_keys(keys) {
-                    println!("{}", key)
+                let keys = fzf_copy_id(&backend, true)?;
+
+                if args.output != OutputFormat::Table {
+                    let selected: HashSet<String> = keys.into_iter().collect();
+                    let ssh_keys = retain_auth_subkeys(gpg_keys(&backend, None)?, |auth_subkey| {
+                        selected.contains(&auth_subkey.export_key_id())
+                    });
+                    return print_json(&backend, &ssh_keys, args.output)
+                }
+
+                for key in get_public_ssh_keys(&backend, keys)? {
+                    println!("{}", format_public_key(&key, args.format, args.host.as_deref())?)
                 }
             }
-        }
+        },
+        SubCommands::Lookup(args) => {
+            // Also the keys without an auth subkey, so that looking them up does not report them as unknown
+            let ssh_keys = all_keys(&backend, None)?;
+            let Some(query) = args.id else {
+                // clap requires one of them without an id
+                let path = args.authorized_keys.or(args.pubkey).unwrap_or_else(|| "-".into());
+                return lookup_ssh_public_keys(&backend, &ssh_keys, &path, args.output)
+            };
+
+            let matches = lookup_keys(&backend, &ssh_keys, &query)?;
+            if matches.is_empty() {
+                return Err(GpgSshError::KeyNotFound(query.to_string()))
+            }
+
+            if args.output != OutputFormat::Table {
+                // The matched auth subkeys, or all of them if the id names the primary key or another subkey
+                let matched: HashSet<&str> = matches.iter()
+                    .filter_map(|key_match| key_match.auth_subkey().map(|auth_subkey| auth_subkey.fingerprint.as_str()))
+                    .collect();
+                let ssh_keys: Vec<SshKeyInfo> = ssh_keys.iter()
+                    .filter(|ssh_key| matches.iter().any(|key_match| key_match.ssh_key.main_key_id == ssh_key.main_key_id))
+                    .cloned()
+                    .map(|mut ssh_key| {
+                        if ssh_key.auth_subkeys.iter().any(|auth_subkey| matched.contains(auth_subkey.fingerprint.as_str())) {
+                            ssh_key.auth_subkeys.retain(|auth_subkey| matched.contains(auth_subkey.fingerprint.as_str()));
+                        }
+                        ssh_key
+                    })
+                    .collect();
+                return print_json(&backend, &ssh_keys, args.output)
+            }
+
+            for (index, key_match) in matches.iter().enumerate() {
+                if index > 0 { println!() }
+                print_key_match(key_match);
+            }
+        },
     }
+
+    Ok(())
 }
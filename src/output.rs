@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{stdout, Write};
+use serde::Serialize;
+use gpg_ssh::Result;
+use gpg_ssh::gpg::{agent_key_infos, get_enabled_keygrip, ssh_public_keys, AuthSubkey, GpgBackend, KeyInfo, SshKeyInfo};
+use gpg_ssh::ssh::SshPublicKey;
+use crate::arguments::OutputFormat;
+
+/// What gpg-agent and SSH make of an auth subkey
+#[derive(Serialize)]
+struct SshStateOutput<'a> {
+    /// Marked with `Use-for-ssh` in gpg-agent
+    enabled: bool,
+    ssh_public_key: Option<String>,
+    ssh_fingerprint: Option<String>,
+    /// What gpg-agent knows about the key (KEYINFO), e.g. whether its passphrase is cached
+    agent_key_info: Option<&'a KeyInfo>,
+}
+
+#[derive(Serialize)]
+struct SshKeyOutput<'a> {
+    #[serde(flatten)]
+    ssh_key: &'a SshKeyInfo,
+    /// The state of each of the `auth_subkeys` of the key, by keygrip
+    ssh: BTreeMap<&'a str, SshStateOutput<'a>>,
+}
+
+/// The key with the state of its auth subkeys, the maps are by keygrip
+fn key_output<'a>(
+    ssh_key: &'a SshKeyInfo,
+    ssh_public_keys: &HashMap<String, Result<SshPublicKey>>,
+    enabled_keygrip: &HashSet<String>,
+    key_infos: &'a HashMap<String, KeyInfo>,
+) -> SshKeyOutput<'a> {
+    SshKeyOutput {
+        ssh_key,
+        ssh: ssh_key.auth_subkeys.iter().map(|auth_subkey| {
+            let ssh_public_key = ssh_public_keys.get(&auth_subkey.keygrip).and_then(|ssh_public_key| ssh_public_key.as_ref().ok());
+            (auth_subkey.keygrip.as_str(), SshStateOutput {
+                enabled: enabled_keygrip.contains(&auth_subkey.keygrip),
+                ssh_fingerprint: ssh_public_key.map(SshPublicKey::fingerprint_sha256),
+                ssh_public_key: ssh_public_key.map(SshPublicKey::to_string),
+                agent_key_info: key_infos.get(&auth_subkey.keygrip),
+            })
+        }).collect(),
+    }
+}
+
+/// Prints the keys as a JSON array or as JSON lines (one object per key)
+pub(crate) fn print_json(backend: &dyn GpgBackend, ssh_keys: &[SshKeyInfo], format: OutputFormat) -> Result<()> {
+    let enabled_keygrip = get_enabled_keygrip(backend)?;
+    let key_infos = agent_key_infos(backend);
+    let ssh_public_keys = ssh_public_keys(backend, ssh_keys)?;
+    let output: Vec<SshKeyOutput> = ssh_keys.iter()
+        .map(|ssh_key| key_output(ssh_key, &ssh_public_keys, &enabled_keygrip, &key_infos))
+        .collect();
+
+    write_json(&output, format)
+}
+
+/// Writes the items as a JSON array or as JSON lines (one object per item)
+pub(crate) fn write_json<T: Serialize>(output: &[T], format: OutputFormat) -> Result<()> {
+    let mut stdout = stdout().lock();
+    if format == OutputFormat::Jsonl {
+        for item in output {
+            serde_json::to_writer(&mut stdout, item).map_err(std::io::Error::from)?;
+            writeln!(stdout)?;
+        }
+    } else {
+        serde_json::to_writer_pretty(&mut stdout, output).map_err(std::io::Error::from)?;
+        writeln!(stdout)?;
+    }
+
+    Ok(())
+}
+
+/// What a line of an `authorized_keys` file turned out to be
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AuthorizedKeyStatus {
+    /// One of the auth subkeys in the keyring
+    Known,
+    /// An SSH public key that is not in the keyring
+    Unknown,
+    /// An SSH public key that is none of the auth subkeys gpg could export, but may be one of the others
+    Unverified,
+    /// Not an SSH public key at all
+    Unparseable,
+}
+
+/// A line of an `authorized_keys` file (or `.pub` file) and the auth subkey it is, if any
+#[derive(Serialize)]
+pub(crate) struct AuthorizedKeyOutput<'a> {
+    /// The line number, starting at 1
+    pub line: usize,
+    pub status: AuthorizedKeyStatus,
+    /// Why the line could not be parsed or told apart from the auth subkeys, for unparseable and unverified lines
+    pub error: Option<String>,
+    pub key_type: Option<&'a str>,
+    pub ssh_fingerprint: Option<String>,
+    pub comment: Option<&'a str>,
+    pub main_key_id: Option<&'a str>,
+    pub main_name: Option<&'a str>,
+    pub auth_subkey: Option<&'a AuthSubkey>,
+}
+
+#[cfg(test)]
+mod tests {
+    use gpg_ssh::gpg::colons::Validity;
+    use gpg_ssh::gpg::{SecretAvailability, Subkey};
+    use super::*;
+
+    #[test]
+    fn json_has_every_field_of_the_key() {
+        let ssh_key = SshKeyInfo {
+            main_key_id: "0E42E055657C72FFB213CF83510DF3503748F565".to_string(),
+            main_name: "Alice <alice@example.com>".to_string(),
+            user_ids: vec!["Alice <alice@example.com>".to_string(), "Alice <alice@work.example>".to_string()],
+            main_validity: Validity::Ultimate,
+            main_expires: None,
+            main_disabled: false,
+            auth_subkeys: vec![AuthSubkey {
+                fingerprint: "5BCC596B65ED955F64C31BBC0C2E24E97AD47E32".to_string(),
+                keygrip: "0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243".to_string(),
+                secret: SecretAvailability::Local,
+                algorithm: "ed25519".to_string(),
+                validity: Validity::Ultimate,
+                created: Some(1792314498),
+                expires: None,
+            }],
+            subkeys: vec![Subkey {
+                fingerprint: "A1B2C3D4E5F60718293A4B5C6D7E8F9012345678".to_string(),
+                algorithm: "cv25519".to_string(),
+                capabilities: "e".to_string(),
+                validity: Validity::Ultimate,
+                created: Some(1792314498),
+                expires: None,
+            }],
+        };
+
+        let keygrip = "0AA0AACADB57E40DC7334BCC3BA6C3EF4D424243";
+        let key_infos = HashMap::new();
+        let output = key_output(&ssh_key, &HashMap::new(), &HashSet::from([keygrip.to_string()]), &key_infos);
+        let json = serde_json::to_value(output).unwrap();
+        assert_eq!(json["main_key_id"], "0E42E055657C72FFB213CF83510DF3503748F565");
+        assert_eq!(json["user_ids"][1], "Alice <alice@work.example>");
+        assert_eq!(json["subkeys"][0]["capabilities"], "e");
+        assert_eq!(json["auth_subkeys"][0]["keygrip"], keygrip);
+        assert_eq!(json["ssh"][keygrip]["enabled"], true);
+    }
+
+    #[test]
+    fn json_reports_unparseable_lines() {
+        let output = AuthorizedKeyOutput {
+            line: 3,
+            status: AuthorizedKeyStatus::Unparseable,
+            error: Some("not a key".to_string()),
+            key_type: None,
+            ssh_fingerprint: None,
+            comment: None,
+            main_key_id: None,
+            main_name: None,
+            auth_subkey: None,
+        };
+        let json = serde_json::to_value(output).unwrap();
+        assert_eq!(json["line"], 3);
+        assert_eq!(json["status"], "unparseable");
+        assert_eq!(json["error"], "not a key");
+    }
+}
@@ -0,0 +1,197 @@
+//! Client for the SSH agent protocol (draft-miller-ssh-agent), to see which keys the agent offers to ssh
+//!
+//! Every message is a `uint32` length followed by the message type and its contents.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use crate::error::{GpgSshError, Result};
+use super::SshPublicKey;
+
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+
+/// Messages larger than this are refused instead of allocated
+const MAX_MESSAGE_LENGTH: usize = 256 * 1024;
+
+/// A key the agent offers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// The key in the SSH wire format, the same as [`SshPublicKey::blob`]
+    pub blob: Vec<u8>,
+    pub comment: String,
+}
+
+impl Identity {
+    /// The key in the OpenSSH format, with the comment of the agent
+    pub fn public_key(&self) -> Result<SshPublicKey> {
+        let key_type = Reader::new(&self.blob).string()?;
+        Ok(SshPublicKey {
+            key_type: String::from_utf8_lossy(key_type).to_string(),
+            blob: self.blob.clone(),
+            comment: Some(self.comment.clone()).filter(|comment| !comment.is_empty()),
+        })
+    }
+}
+
+/// Reads the SSH wire format: big endian integers and length prefixed strings
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < length {
+            return Err(GpgSshError::SshAgent("The message of the SSH agent is truncated".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+}
+
+/// A connection to an SSH agent
+#[derive(Debug)]
+pub struct SshAgentClient<S> {
+    stream: S,
+}
+
+impl SshAgentClient<UnixStream> {
+    /// Connects to the socket of the agent, usually `$SSH_AUTH_SOCK`
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket).map_err(|err| GpgSshError::SshAgent(
+            format!("Could not connect to the SSH agent at {}: {err}", socket.display())
+        ))?;
+        Ok(SshAgentClient::new(stream))
+    }
+}
+
+impl<S: Read + Write> SshAgentClient<S> {
+    pub fn new(stream: S) -> Self {
+        SshAgentClient { stream }
+    }
+
+    /// Sends a request and returns the type and contents of the answer
+    fn request(&mut self, message_type: u8, contents: &[u8]) -> Result<(u8, Vec<u8>)> {
+        let io_error = |err: std::io::Error| GpgSshError::SshAgent(format!("Could not talk to the SSH agent: {err}"));
+
+        let mut message = Vec::with_capacity(contents.len() + 5);
+        message.extend((contents.len() as u32 + 1).to_be_bytes());
+        message.push(message_type);
+        message.extend(contents);
+        self.stream.write_all(&message).map_err(io_error)?;
+        self.stream.flush().map_err(io_error)?;
+
+        let mut length = [0; 4];
+        self.stream.read_exact(&mut length).map_err(io_error)?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length == 0 || length > MAX_MESSAGE_LENGTH {
+            return Err(GpgSshError::SshAgent(format!("The SSH agent sent a message of {length} bytes")));
+        }
+        let mut answer = vec![0; length];
+        self.stream.read_exact(&mut answer).map_err(io_error)?;
+
+        let contents = answer.split_off(1);
+        Ok((answer[0], contents))
+    }
+
+    /// The keys the agent offers (`SSH_AGENTC_REQUEST_IDENTITIES`)
+    pub fn request_identities(&mut self) -> Result<Vec<Identity>> {
+        let (message_type, contents) = self.request(SSH_AGENTC_REQUEST_IDENTITIES, &[])?;
+        if message_type != SSH_AGENT_IDENTITIES_ANSWER {
+            return Err(GpgSshError::SshAgent(format!("The SSH agent did not list its keys (message type {message_type})")));
+        }
+
+        let mut reader = Reader::new(&contents);
+        let count = reader.u32()?;
+        (0..count).map(|_| Ok(Identity {
+            blob: reader.string()?.to_vec(),
+            comment: String::from_utf8_lossy(reader.string()?).to_string(),
+        })).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::ssh::put_string;
+
+    const SSH_AGENT_FAILURE: u8 = 5;
+    const ALICE: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFYmEqP9At0MNxGqeaoiLouSvzmTbE1MRiWLgRQI3RCe openpgp:0x7AD47E32";
+
+    /// Replays the canned answer and records the requests
+    struct FakeAgent {
+        answer: Cursor<Vec<u8>>,
+        requests: Vec<u8>,
+    }
+
+    impl Read for FakeAgent {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.answer.read(buf)
+        }
+    }
+
+    impl Write for FakeAgent {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.requests.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn client(message_type: u8, contents: &[u8]) -> SshAgentClient<FakeAgent> {
+        let mut answer = (contents.len() as u32 + 1).to_be_bytes().to_vec();
+        answer.push(message_type);
+        answer.extend(contents);
+        SshAgentClient::new(FakeAgent { answer: Cursor::new(answer), requests: Vec::new() })
+    }
+
+    #[test]
+    fn request_identities() {
+        let alice = SshPublicKey::parse(ALICE).unwrap();
+        let mut contents = 1u32.to_be_bytes().to_vec();
+        put_string(&mut contents, &alice.blob);
+        put_string(&mut contents, b"(none)");
+
+        let mut client = client(SSH_AGENT_IDENTITIES_ANSWER, &contents);
+        let identities = client.request_identities().unwrap();
+
+        assert_eq!(client.stream.requests, vec![0, 0, 0, 1, SSH_AGENTC_REQUEST_IDENTITIES]);
+        assert_eq!(identities, vec![Identity { blob: alice.blob.clone(), comment: "(none)".to_string() }]);
+        assert_eq!(identities[0].public_key().unwrap().key_type, "ssh-ed25519");
+    }
+
+    #[test]
+    fn failures_are_errors() {
+        assert!(matches!(client(SSH_AGENT_FAILURE, &[]).request_identities(), Err(GpgSshError::SshAgent(_))));
+        assert!(matches!(
+            client(SSH_AGENT_IDENTITIES_ANSWER, &[0, 0, 0, 1]).request_identities(),
+            Err(GpgSshError::SshAgent(_)),
+        ));
+    }
+
+    #[test]
+    fn io_errors_are_ssh_agent_errors() {
+        // The agent hangs up without answering
+        let mut client = SshAgentClient::new(FakeAgent { answer: Cursor::new(Vec::new()), requests: Vec::new() });
+
+        assert!(matches!(client.request_identities(), Err(GpgSshError::SshAgent(_))));
+    }
+}
@@ -0,0 +1,242 @@
+//! Other encodings of an SSH public key: RFC 4716, PEM (SPKI), JWK and `known_hosts`
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use serde_json::json;
+use crate::error::{GpgSshError, Result};
+use super::SshPublicKey;
+
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_NISTP256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_NISTP384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const OID_NISTP521: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+
+/// The fields of the SSH wire format of a key
+enum KeyParts<'a> {
+    Ed25519 { point: &'a [u8] },
+    /// Both as `mpint`, i.e. big endian two's complement
+    Rsa { e: &'a [u8], n: &'a [u8] },
+    /// The point is uncompressed: `0x04 || x || y`
+    Ecdsa { curve: &'a str, point: &'a [u8] },
+}
+
+/// Reads the next `string` of the SSH wire format
+fn read_string<'a>(blob: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let truncated = || GpgSshError::Parse("The SSH public key is truncated".to_string());
+    let length = blob.get(..4).ok_or_else(truncated)?;
+    let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+    let string = blob.get(4..4 + length).ok_or_else(truncated)?;
+    *blob = &blob[4 + length..];
+    Ok(string)
+}
+
+/// The DER encoding of a tag, its length and the content
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    let length = content.len().to_be_bytes();
+    let length = &length[length.iter().take_while(|byte| **byte == 0).count()..];
+    match length {
+        [] => der.push(0),
+        [short] if *short < 0x80 => der.push(*short),
+        long => {
+            der.push(0x80 | long.len() as u8);
+            der.extend(long);
+        },
+    }
+    der.extend(content);
+    der
+}
+
+fn der_sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    der(0x30, &items.concat())
+}
+
+/// A BIT STRING without unused bits
+fn der_bit_string(bytes: &[u8]) -> Vec<u8> {
+    der(0x03, &[&[0], bytes].concat())
+}
+
+/// The curve as JWK (`crv`) and DER (named curve OID) name it
+fn curve_names(curve: &str) -> Result<(&'static str, &'static [u8])> {
+    match curve {
+        "nistp256" => Ok(("P-256", OID_NISTP256)),
+        "nistp384" => Ok(("P-384", OID_NISTP384)),
+        "nistp521" => Ok(("P-521", OID_NISTP521)),
+        curve => Err(GpgSshError::Parse(format!("Unknown ECDSA curve `{curve}`"))),
+    }
+}
+
+/// Splits the base64 encoding into lines of `width` characters
+fn wrap_base64(bytes: &[u8], width: usize) -> String {
+    let encoded = STANDARD.encode(bytes);
+    encoded.as_bytes().chunks(width)
+        .map(|line| format!("{}\n", String::from_utf8_lossy(line)))
+        .collect()
+}
+
+impl SshPublicKey {
+    fn parts(&self) -> Result<KeyParts<'_>> {
+        let mut blob = self.blob.as_slice();
+        let key_type = read_string(&mut blob)?;
+        match key_type {
+            b"ssh-ed25519" => Ok(KeyParts::Ed25519 { point: read_string(&mut blob)? }),
+            b"ssh-rsa" => {
+                let e = read_string(&mut blob)?;
+                let n = read_string(&mut blob)?;
+                Ok(KeyParts::Rsa { e, n })
+            },
+            key_type if key_type.starts_with(b"ecdsa-sha2-") => {
+                let curve = std::str::from_utf8(read_string(&mut blob)?)
+                    .map_err(|_| GpgSshError::Parse("The curve of the ECDSA key is not valid UTF-8".to_string()))?;
+                Ok(KeyParts::Ecdsa { curve, point: read_string(&mut blob)? })
+            },
+            key_type => Err(GpgSshError::Parse(format!(
+                "Converting `{}` keys is not supported", String::from_utf8_lossy(key_type)
+            ))),
+        }
+    }
+
+    /// The key in the RFC 4716 format (`ssh-keygen -e`), with the comment as `Comment` header
+    pub fn to_rfc4716(&self) -> String {
+        let comment = self.comment.as_ref()
+            .map(|comment| format!("Comment: \"{}\"\n", comment.replace('\\', "\\\\").replace('"', "\\\"")))
+            .unwrap_or_default();
+        format!(
+            "---- BEGIN SSH2 PUBLIC KEY ----\n{comment}{}---- END SSH2 PUBLIC KEY ----\n",
+            wrap_base64(&self.blob, 70),
+        )
+    }
+
+    /// The key as PEM encoded SubjectPublicKeyInfo (`-----BEGIN PUBLIC KEY-----`), as OpenSSL reads it
+    pub fn to_pem(&self) -> Result<String> {
+        let spki = match self.parts()? {
+            KeyParts::Ed25519 { point } => der_sequence(&[
+                der_sequence(&[der(0x06, OID_ED25519)]),
+                der_bit_string(point),
+            ]),
+            KeyParts::Rsa { e, n } => der_sequence(&[
+                der_sequence(&[der(0x06, OID_RSA_ENCRYPTION), der(0x05, &[])]),
+                // An mpint is already the minimal two's complement DER expects of an INTEGER
+                der_bit_string(&der_sequence(&[der(0x02, n), der(0x02, e)])),
+            ]),
+            KeyParts::Ecdsa { curve, point } => der_sequence(&[
+                der_sequence(&[der(0x06, OID_EC_PUBLIC_KEY), der(0x06, curve_names(curve)?.1)]),
+                der_bit_string(point),
+            ]),
+        };
+        Ok(format!("-----BEGIN PUBLIC KEY-----\n{}-----END PUBLIC KEY-----\n", wrap_base64(&spki, 64)))
+    }
+
+    /// The key as JSON Web Key (RFC 7517), with the comment as `kid`
+    pub fn to_jwk(&self) -> Result<String> {
+        let mut jwk = match self.parts()? {
+            KeyParts::Ed25519 { point } => json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": URL_SAFE_NO_PAD.encode(point),
+            }),
+            KeyParts::Rsa { e, n } => json!({
+                "kty": "RSA",
+                // JWK integers have no sign byte
+                "n": URL_SAFE_NO_PAD.encode(n.strip_prefix(&[0]).unwrap_or(n)),
+                "e": URL_SAFE_NO_PAD.encode(e.strip_prefix(&[0]).unwrap_or(e)),
+            }),
+            KeyParts::Ecdsa { curve, point } => {
+                let (x, y) = match point.split_first() {
+                    Some((0x04, coordinates)) => coordinates.split_at(coordinates.len() / 2),
+                    _ => return Err(GpgSshError::Parse("The ECDSA point is not uncompressed".to_string())),
+                };
+                json!({
+                    "kty": "EC",
+                    "crv": curve_names(curve)?.0,
+                    "x": URL_SAFE_NO_PAD.encode(x),
+                    "y": URL_SAFE_NO_PAD.encode(y),
+                })
+            },
+        };
+        if let Some(comment) = &self.comment {
+            jwk["kid"] = json!(comment);
+        }
+        Ok(jwk.to_string())
+    }
+
+    /// A line of `known_hosts` for the host, e.g. `example.com ssh-ed25519 AAAA...`
+    pub fn to_known_hosts(&self, host: &str) -> String {
+        format!("{host} {} {}", self.key_type, STANDARD.encode(&self.blob))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `gpg --export-ssh-key` of the auth subkeys of `gpg/testdata/export.gpg`
+    const KEYS: &str = include_str!("../gpg/testdata/export_ssh_keys.txt");
+
+    fn key(index: usize) -> SshPublicKey {
+        SshPublicKey::parse(KEYS.lines().nth(index).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rfc4716_like_ssh_keygen() {
+        // ssh-keygen -e -f key.pub, with the comment instead of the default one
+        assert_eq!(key(0).to_rfc4716(), "\
+---- BEGIN SSH2 PUBLIC KEY ----
+Comment: \"openpgp:0x5835E8BE\"
+AAAAC3NzaC1lZDI1NTE5AAAAIG8+M7G+oZHzLanucxkeMEoVqSKizGw2M4xfGNO4q/MD
+---- END SSH2 PUBLIC KEY ----
+");
+    }
+
+    #[test]
+    fn pem_like_openssl() {
+        // ssh-keygen cannot convert ed25519 keys, `openssl pkey -pubin` reads the same 32 bytes as the SSH key
+        assert_eq!(key(0).to_pem().unwrap(), "\
+-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEAbz4zsb6hkfMtqe5zGR4wShWpIqLMbDYzjF8Y07ir8wM=
+-----END PUBLIC KEY-----
+");
+        // ssh-keygen -e -m PKCS8 -f key.pub of the RSA and ECDSA keys
+        for (index, expected) in include_str!("testdata/pkcs8.pem").split_inclusive("-----END PUBLIC KEY-----\n").enumerate() {
+            assert_eq!(key(index + 1).to_pem().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn jwk_per_key_type() {
+        let jwk: serde_json::Value = serde_json::from_str(&key(0).to_jwk().unwrap()).unwrap();
+        assert_eq!(jwk, json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": "bz4zsb6hkfMtqe5zGR4wShWpIqLMbDYzjF8Y07ir8wM",
+            "kid": "openpgp:0x5835E8BE",
+        }));
+
+        let jwk: serde_json::Value = serde_json::from_str(&key(1).to_jwk().unwrap()).unwrap();
+        assert_eq!((jwk["kty"].as_str(), jwk["e"].as_str()), (Some("RSA"), Some("AQAB")));
+
+        for (index, crv, length) in [(2, "P-256", 43), (3, "P-384", 64), (4, "P-521", 88)] {
+            let jwk: serde_json::Value = serde_json::from_str(&key(index).to_jwk().unwrap()).unwrap();
+            assert_eq!(jwk["crv"], crv);
+            assert_eq!(jwk["x"].as_str().unwrap().len(), length);
+            assert_eq!(jwk["y"].as_str().unwrap().len(), length);
+        }
+    }
+
+    #[test]
+    fn known_hosts_line() {
+        assert_eq!(
+            key(0).to_known_hosts("example.com"),
+            "example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG8+M7G+oZHzLanucxkeMEoVqSKizGw2M4xfGNO4q/MD",
+        );
+    }
+
+    #[test]
+    fn unsupported_key_type() {
+        let key = SshPublicKey { key_type: "ssh-dss".to_string(), blob: b"\0\0\0\x07ssh-dss".to_vec(), comment: None };
+        assert!(matches!(key.to_pem(), Err(GpgSshError::Parse(_))));
+        assert!(matches!(key.to_jwk(), Err(GpgSshError::Parse(_))));
+    }
+}
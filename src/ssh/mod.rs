@@ -0,0 +1,9 @@
+//! Handling of SSH public keys and of the SSH agent
+
+mod public_key;
+mod agent;
+mod format;
+
+pub use public_key::SshPublicKey;
+pub(crate) use public_key::{put_mpint, put_string};
+pub use agent::{Identity, SshAgentClient};
@@ -0,0 +1,159 @@
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use crate::error::{GpgSshError, Result};
+
+/// Appends a `string` of the SSH wire format: the length as `uint32` and the bytes
+pub(crate) fn put_string(buffer: &mut Vec<u8>, string: &[u8]) {
+    buffer.extend((string.len() as u32).to_be_bytes());
+    buffer.extend(string);
+}
+
+/// Appends an `mpint` of the SSH wire format (RFC 4251) for the big endian unsigned integer,
+/// without leading zeros but with a zero byte if the highest bit is set, so it is not read as negative
+pub(crate) fn put_mpint(buffer: &mut Vec<u8>, integer: &[u8]) {
+    let integer = &integer[integer.iter().take_while(|byte| **byte == 0).count()..];
+    if integer.first().is_some_and(|byte| byte & 0x80 != 0) {
+        buffer.extend((integer.len() as u32 + 1).to_be_bytes());
+        buffer.push(0);
+        buffer.extend(integer);
+    } else {
+        put_string(buffer, integer);
+    }
+}
+
+/// A public key in the OpenSSH format, e.g. `ssh-ed25519 AAAA... openpgp:0xAAB1D083`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshPublicKey {
+    /// The key type, e.g. `ssh-ed25519`
+    pub key_type: String,
+    /// The key in the SSH wire format (the base64 decoded part of the line)
+    pub blob: Vec<u8>,
+    pub comment: Option<String>,
+}
+
+impl SshPublicKey {
+    /// Parses `<key type> <base64 blob> [comment]`, the error tells what is wrong, the caller adds the line
+    fn parse_columns(key: &str) -> std::result::Result<Self, String> {
+        let mut columns = key.split_whitespace();
+        let (Some(key_type), Some(blob)) = (columns.next(), columns.next()) else {
+            return Err("Expected `<key type> <base64>`".to_string())
+        };
+        let blob = STANDARD.decode(blob).map_err(|err| format!("Invalid base64 ({err})"))?;
+        let comment = Some(columns.collect::<Vec<&str>>().join(" ")).filter(|comment| !comment.is_empty());
+
+        Ok(SshPublicKey { key_type: key_type.to_string(), blob, comment })
+    }
+
+    /// Parses a line in the OpenSSH format: `<key type> <base64 blob> [comment]`
+    pub fn parse(line: &str) -> Result<Self> {
+        Self::parse_columns(line)
+            .map_err(|reason| GpgSshError::Parse(format!("{reason} in the SSH public key: {line}")))
+    }
+
+    /// Parses a line of `authorized_keys`: `[options] <key type> <base64 blob> [comment]`
+    ///
+    /// The options, e.g. `from="10.0.0.0/8",command="echo hi"`, are skipped, spaces in quotes included.
+    /// The error shows the whole line, options included.
+    pub fn parse_authorized_key(line: &str) -> Result<Self> {
+        let line = line.trim();
+        let first = line.split_whitespace().next().unwrap_or_default();
+        let key = if ["ssh-", "ecdsa-sha2-", "sk-"].iter().any(|prefix| first.starts_with(prefix)) {
+            line
+        } else {
+            let (mut quoted, mut escaped) = (false, false);
+            let options_end = line.char_indices().find(|(_, char)| match char {
+                _ if escaped => { escaped = false; false },
+                '\\' => { escaped = true; false },
+                '"' => { quoted = !quoted; false },
+                char => char.is_whitespace() && !quoted,
+            }).map_or(line.len(), |(index, _)| index);
+            &line[options_end..]
+        };
+
+        Self::parse_columns(key)
+            .map_err(|reason| GpgSshError::Parse(format!("{reason} in the authorized_keys line: {line}")))
+    }
+
+    /// The fingerprint as shown by `ssh-keygen -l`, e.g. `SHA256:Dp6Y...`
+    pub fn fingerprint_sha256(&self) -> String {
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(&self.blob)))
+    }
+
+    /// The legacy fingerprint of `ssh-keygen -l -E md5`, e.g. `MD5:d7:29:fb:...`
+    pub fn fingerprint_md5(&self) -> String {
+        let hex: Vec<String> = Md5::digest(&self.blob).iter().map(|byte| format!("{byte:02x}")).collect();
+        format!("MD5:{}", hex.join(":"))
+    }
+}
+
+impl std::fmt::Display for SshPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.key_type, STANDARD.encode(&self.blob))?;
+        if let Some(comment) = &self.comment {
+            write!(f, " {comment}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFYmEqP9At0MNxGqeaoiLouSvzmTbE1MRiWLgRQI3RCe openpgp:0x7AD47E32";
+
+    #[test]
+    fn parse_and_display() {
+        let key = SshPublicKey::parse(ALICE).unwrap();
+
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(key.blob.len(), 51);
+        assert_eq!(key.comment.as_deref(), Some("openpgp:0x7AD47E32"));
+        assert_eq!(key.to_string(), ALICE);
+    }
+
+    #[test]
+    fn fingerprint_matches_ssh_keygen() {
+        let key = SshPublicKey::parse(ALICE).unwrap();
+
+        // ssh-keygen -l -f alice.pub
+        assert_eq!(key.fingerprint_sha256(), "SHA256:V3A7MNo4FQaolbTZfYU78oN6sJ+fAHb8DW0h1ihZIjA");
+        // ssh-keygen -l -E md5 -f alice.pub
+        assert_eq!(key.fingerprint_md5(), "MD5:d2:fa:51:8a:21:73:8e:5d:11:71:29:5c:45:53:a6:78");
+    }
+
+    #[test]
+    fn parse_authorized_keys_lines() {
+        let key = SshPublicKey::parse(ALICE).unwrap();
+        let parse = |line: &str| SshPublicKey::parse_authorized_key(line).unwrap();
+
+        assert_eq!(parse(ALICE), key);
+        assert_eq!(parse(&format!("restrict,pty {ALICE}")), key);
+        assert_eq!(parse(&format!(r#"from="10.0.0.0/8",command="echo \"ssh-rsa x\" done" {ALICE}"#)), key);
+        assert!(matches!(SshPublicKey::parse_authorized_key("restrict"), Err(GpgSshError::Parse(_))));
+    }
+
+    #[test]
+    fn authorized_key_error_shows_the_whole_line() {
+        let err = SshPublicKey::parse_authorized_key("garbage line").unwrap_err();
+
+        assert!(err.to_string().ends_with(": garbage line"), "{err}");
+    }
+
+    #[test]
+    fn mpint_encoding() {
+        // The examples of RFC 4251, section 5
+        let mpint = |integer: &[u8]| { let mut buffer = Vec::new(); put_mpint(&mut buffer, integer); buffer };
+        assert_eq!(mpint(&[]), [0, 0, 0, 0]);
+        assert_eq!(mpint(&[0, 0x80]), [0, 0, 0, 2, 0, 0x80]);
+        assert_eq!(mpint(&[0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7]), [0, 0, 0, 8, 0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7]);
+    }
+
+    #[test]
+    fn parse_invalid_keys() {
+        assert!(SshPublicKey::parse("ssh-ed25519").is_err());
+        assert!(SshPublicKey::parse("ssh-ed25519 not*base64").is_err());
+    }
+}